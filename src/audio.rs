@@ -0,0 +1,398 @@
+use std::{
+  fmt,
+  fs::File,
+  io::{self, BufReader},
+  path::Path,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc,
+  },
+  thread,
+  time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use libpulse_binding::{
+  error::PAErr,
+  sample::{Format, Spec},
+  stream::Direction,
+};
+use libpulse_simple_binding::Simple;
+
+use crate::{
+  replay::{Packet, TrackKind},
+  schedule::Clock,
+};
+
+/// Sample rate used for the captured audio, Opus works natively in 48 kHz
+pub const SAMPLE_RATE: u32 = 48_000;
+
+/// Length of one Opus frame in milliseconds
+const FRAME_LENGTH: u64 = 20;
+
+/// SeekPreRoll of the WebM track in nanoseconds, as recommended for Opus
+pub const SEEK_PRE_ROLL: u64 = 80_000_000;
+
+/// Rate of the samples counted in the OpusHead, whatever the input rate is
+const OPUS_RATE: u64 = 48_000;
+
+/// Input sample rates Opus can encode
+const OPUS_INPUT_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+
+/// Source of interleaved 16-bit PCM samples
+pub trait AudioSource {
+  fn sample_rate(&self) -> u32;
+  fn channels(&self) -> u16;
+
+  /// Fill the whole buffer with samples, blocking until they are available
+  ///
+  /// Returns the number of samples read, less than the length of the buffer
+  /// only at the end of the stream.
+  fn read(&mut self, buffer: &mut [i16]) -> io::Result<usize>;
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+pub enum AudioKind {
+  /// Record video only
+  #[default]
+  None,
+  /// Record a PulseAudio/PipeWire source, the monitor of the default output by default
+  Pulse,
+  /// Read the audio from a WAV file
+  Wav,
+}
+
+impl fmt::Display for AudioKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let string = match self {
+      Self::None => "none",
+      Self::Pulse => "pulse",
+      Self::Wav => "wav",
+    };
+    write!(f, "{string}")
+  }
+}
+
+/// Records a PulseAudio (or PipeWire through pipewire-pulse) source
+pub struct PulseSource {
+  simple: Simple,
+  channels: u16,
+  bytes: Vec<u8>,
+}
+
+impl PulseSource {
+  /// Default device, the monitor of the default output
+  pub const DEFAULT_DEVICE: &'static str = "@DEFAULT_MONITOR@";
+
+  pub fn new(device: Option<&str>) -> io::Result<Self> {
+    let channels = 2;
+    let spec = Spec {
+      format: Format::S16le,
+      channels: channels as u8,
+      rate: SAMPLE_RATE,
+    };
+
+    let simple = Simple::new(
+      None,
+      "shadowplay",
+      Direction::Record,
+      Some(device.unwrap_or(Self::DEFAULT_DEVICE)),
+      "screen recording",
+      &spec,
+      None,
+      None,
+    )
+    .map_err(pulse_error)?;
+
+    Ok(Self {
+      simple,
+      channels,
+      bytes: Vec::new(),
+    })
+  }
+}
+
+impl AudioSource for PulseSource {
+  fn sample_rate(&self) -> u32 {
+    SAMPLE_RATE
+  }
+
+  fn channels(&self) -> u16 {
+    self.channels
+  }
+
+  fn read(&mut self, buffer: &mut [i16]) -> io::Result<usize> {
+    self.bytes.resize(buffer.len() * 2, 0);
+
+    self.simple.read(&mut self.bytes).map_err(pulse_error)?;
+
+    for (sample, bytes) in buffer.iter_mut().zip(self.bytes.chunks_exact(2)) {
+      *sample = i16::from_le_bytes([bytes[0], bytes[1]]);
+    }
+
+    Ok(buffer.len())
+  }
+}
+
+fn pulse_error(e: PAErr) -> io::Error {
+  io::Error::new(io::ErrorKind::Other, format!("PulseAudio: {e}"))
+}
+
+/// Reads samples from a 16-bit WAV file, mostly useful for testing
+///
+/// The samples are given out in real time, like a live source would, so the
+/// recording keeps up with them.
+pub struct WavSource {
+  reader: hound::WavReader<BufReader<File>>,
+  /// When the first samples were read
+  start: Option<Instant>,
+  /// Number of samples read so far, of all the channels
+  read: u64,
+}
+
+impl WavSource {
+  pub fn new(path: &Path) -> io::Result<Self> {
+    let reader =
+      hound::WavReader::open(path).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    let spec = reader.spec();
+    if spec.bits_per_sample != 16 || spec.sample_format != hound::SampleFormat::Int {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Only 16-bit integer WAV files are supported",
+      ));
+    }
+
+    Ok(Self {
+      reader,
+      start: None,
+      read: 0,
+    })
+  }
+}
+
+impl AudioSource for WavSource {
+  fn sample_rate(&self) -> u32 {
+    self.reader.spec().sample_rate
+  }
+
+  fn channels(&self) -> u16 {
+    self.reader.spec().channels
+  }
+
+  fn read(&mut self, buffer: &mut [i16]) -> io::Result<usize> {
+    let mut samples = self.reader.samples::<i16>();
+    let mut read = 0;
+
+    for (out, sample) in buffer.iter_mut().zip(&mut samples) {
+      *out = sample.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+      read += 1;
+    }
+
+    // wait until the samples would have been captured
+    let spec = self.reader.spec();
+    let start = *self.start.get_or_insert_with(Instant::now);
+    self.read += read as u64;
+    let rate = u64::from(spec.sample_rate) * u64::from(spec.channels);
+    let due = start + Duration::from_nanos(self.read * 1_000_000_000 / rate.max(1));
+    thread::sleep(due.saturating_duration_since(Instant::now()));
+
+    Ok(read)
+  }
+}
+
+/// Parameters of the encoded audio track
+#[derive(Debug, Clone)]
+pub struct AudioFormat {
+  pub sample_rate: u32,
+  pub channels: u16,
+  /// OpusHead, the CodecPrivate of the WebM track
+  pub codec_private: Vec<u8>,
+}
+
+impl AudioFormat {
+  /// CodecDelay of the WebM track in nanoseconds, the pre-skip of the
+  /// OpusHead
+  pub fn codec_delay(&self) -> u64 {
+    let pre_skip = self
+      .codec_private
+      .get(10..12)
+      .map_or(0, |bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+
+    u64::from(pre_skip) * 1_000_000_000 / OPUS_RATE
+  }
+}
+
+pub struct OpusEncoder {
+  encoder: opus::Encoder,
+  format: AudioFormat,
+  output: Vec<u8>,
+}
+
+impl OpusEncoder {
+  /// `bitrate` is in kbps
+  pub fn new(sample_rate: u32, channels: u16, bitrate: u32) -> io::Result<Self> {
+    let opus_channels = match channels {
+      1 => opus::Channels::Mono,
+      2 => opus::Channels::Stereo,
+      _ => {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          "Only mono and stereo audio is supported",
+        ))
+      }
+    };
+
+    if !OPUS_INPUT_RATES.contains(&sample_rate) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Opus can't encode audio at {sample_rate} Hz, only at 8, 12, 16, 24 or 48 kHz"),
+      ));
+    }
+
+    let map_err = |e: opus::Error| io::Error::new(io::ErrorKind::Other, e);
+
+    let mut encoder =
+      opus::Encoder::new(sample_rate, opus_channels, opus::Application::Audio).map_err(map_err)?;
+    encoder
+      .set_bitrate(opus::Bitrate::Bits(bitrate as i32 * 1000))
+      .map_err(map_err)?;
+
+    // the lookahead is in samples of the input rate
+    let lookahead = encoder.get_lookahead().map_err(map_err)? as u64;
+    let pre_skip = (lookahead * OPUS_RATE / u64::from(sample_rate)) as u16;
+
+    // https://www.rfc-editor.org/rfc/rfc7845#section-5.1
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(channels as u8);
+    head.extend(pre_skip.to_le_bytes());
+    head.extend(sample_rate.to_le_bytes());
+    head.extend(0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family
+
+    Ok(Self {
+      encoder,
+      format: AudioFormat {
+        sample_rate,
+        channels,
+        codec_private: head,
+      },
+      output: vec![0; 4000],
+    })
+  }
+
+  pub fn format(&self) -> &AudioFormat {
+    &self.format
+  }
+
+  /// Number of interleaved samples in one frame
+  pub fn frame_size(&self) -> usize {
+    (self.format.sample_rate as u64 * FRAME_LENGTH / 1000) as usize * self.format.channels as usize
+  }
+
+  pub fn encode(&mut self, samples: &[i16]) -> io::Result<&[u8]> {
+    let length = self
+      .encoder
+      .encode(samples, &mut self.output)
+      .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    Ok(&self.output[..length])
+  }
+}
+
+/// Capture and encode the audio on a separate thread
+///
+/// The timestamps of the packets are in milliseconds of the `clock`, the same
+/// one the video uses, and the samples captured while it's paused are thrown
+/// away. The channel is closed when the source ends or after `stop` is set.
+pub fn spawn(
+  mut source: Box<dyn AudioSource + Send>,
+  bitrate: u32,
+  clock: Arc<Clock>,
+  stop: Arc<AtomicBool>,
+) -> io::Result<(AudioFormat, mpsc::Receiver<Packet>)> {
+  let mut encoder = OpusEncoder::new(source.sample_rate(), source.channels(), bitrate)?;
+  let format = encoder.format().clone();
+
+  let (sender, receiver) = mpsc::channel();
+
+  thread::spawn(move || {
+    let mut samples = vec![0; encoder.frame_size()];
+    let mut first = None;
+    let mut frames = 0;
+
+    while !stop.load(Ordering::Acquire) {
+      match source.read(&mut samples) {
+        Ok(read) if read == samples.len() => {}
+        // the last incomplete frame is dropped
+        Ok(_) => break,
+        Err(e) => {
+          crate::error(format!("Can't read audio: {e}"));
+          break;
+        }
+      }
+
+      // the samples read while paused are thrown away
+      if clock.is_paused() {
+        continue;
+      }
+
+      // the first samples were captured one frame before they were read,
+      // from there on the time is counted in samples to avoid drifting
+      let first = *first.get_or_insert_with(|| {
+        clock
+          .elapsed()
+          .saturating_sub(Duration::from_millis(FRAME_LENGTH))
+          .as_millis() as u64
+      });
+
+      let data = match encoder.encode(&samples) {
+        Ok(data) => data.to_vec(),
+        Err(e) => {
+          crate::error(format!("Can't encode audio: {e}"));
+          break;
+        }
+      };
+
+      let packet = Packet {
+        kind: TrackKind::Audio,
+        data,
+        pts: first + frames * FRAME_LENGTH,
+        key: true,
+      };
+      frames += 1;
+
+      if sender.send(packet).is_err() {
+        break;
+      }
+    }
+  });
+
+  Ok((format, receiver))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn codec_delay_is_the_pre_skip() {
+    let mut head = b"OpusHead".to_vec();
+    head.extend([1, 2]);
+    head.extend(312_u16.to_le_bytes());
+    let format = AudioFormat {
+      sample_rate: 48_000,
+      channels: 2,
+      codec_private: head,
+    };
+
+    assert_eq!(format.codec_delay(), 6_500_000);
+  }
+
+  #[test]
+  fn unsupported_sample_rates_are_rejected() {
+    let error = OpusEncoder::new(44_100, 2, 128).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+  }
+}
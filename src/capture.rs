@@ -0,0 +1,435 @@
+use std::{
+  fmt, io,
+  ops::{Deref, Range},
+};
+
+use clap::ValueEnum;
+
+/// Most displays looked for with captrs, which can't list them
+const MAX_CAPTRS_DISPLAYS: usize = 16;
+
+/// Layout of a single pixel in the captured frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+  /// 8 bits per channel in the order blue, green, red, alpha/padding
+  Bgra,
+}
+
+impl PixelFormat {
+  pub fn bytes_per_pixel(self) -> usize {
+    match self {
+      Self::Bgra => 4,
+    }
+  }
+}
+
+enum Pixels<'a> {
+  Scrap(scrap::Frame<'a>),
+  Borrowed(&'a [u8]),
+}
+
+/// Captured frame borrowed from its source
+pub struct Frame<'a> {
+  pixels: Pixels<'a>,
+  /// Part of the pixels that belongs to the frame
+  range: Range<usize>,
+  /// Length of one row in bytes, the rows may be padded
+  pub stride: usize,
+}
+
+impl<'a> Frame<'a> {
+  fn scrap(frame: scrap::Frame<'a>, stride: usize) -> Self {
+    Self {
+      range: 0..frame.len(),
+      pixels: Pixels::Scrap(frame),
+      stride,
+    }
+  }
+
+  fn borrowed(data: &'a [u8], stride: usize) -> Self {
+    Self {
+      pixels: Pixels::Borrowed(data),
+      range: 0..data.len(),
+      stride,
+    }
+  }
+
+  /// Narrow the frame to `length` bytes starting at `offset`, keeping the
+  /// stride, so the rows of a region are read in place
+  fn slice(mut self, offset: usize, length: usize) -> Self {
+    let start = self.range.start + offset;
+    self.range = start..start + length;
+    self
+  }
+}
+
+impl Deref for Frame<'_> {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    let pixels: &[u8] = match &self.pixels {
+      Pixels::Scrap(frame) => frame,
+      Pixels::Borrowed(data) => data,
+    };
+    &pixels[self.range.clone()]
+  }
+}
+
+/// Source of raw screen frames
+pub trait CaptureSource {
+  fn width(&self) -> usize;
+  fn height(&self) -> usize;
+  fn pixel_format(&self) -> PixelFormat;
+
+  /// Grab the next frame
+  ///
+  /// Fails with [`io::ErrorKind::WouldBlock`] when there is no new frame yet.
+  fn frame(&mut self) -> io::Result<Frame<'_>>;
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, Default)]
+pub enum SourceKind {
+  /// Capture the screen using scrap
+  #[default]
+  Scrap,
+  /// Capture the screen using captrs
+  Captrs,
+  /// Generate a moving test pattern, doesn't need a display
+  Test,
+}
+
+impl SourceKind {
+  /// Width and height of the displays the source can capture, in the order of
+  /// their indices
+  pub fn displays(self) -> io::Result<Vec<(usize, usize)>> {
+    match self {
+      Self::Scrap => Ok(
+        scrap::Display::all()?
+          .iter()
+          .map(|display| (display.width(), display.height()))
+          .collect(),
+      ),
+      Self::Captrs => {
+        // captrs can't list the displays. On Windows the indices are tried
+        // until one fails, elsewhere it always captures the whole screen.
+        let count = if cfg!(windows) {
+          MAX_CAPTRS_DISPLAYS
+        } else {
+          1
+        };
+
+        let mut displays = Vec::new();
+        for index in 0..count {
+          match captrs::Capturer::new(index) {
+            Ok(capturer) => {
+              let (width, height) = capturer.geometry();
+              displays.push((width as usize, height as usize));
+            }
+            // the first index that fails is past the last display
+            Err(_) if index > 0 => break,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+          }
+        }
+
+        Ok(displays)
+      }
+      Self::Test => Ok(vec![TestPattern::SIZE]),
+    }
+  }
+}
+
+impl fmt::Display for SourceKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let string = match self {
+      Self::Scrap => "scrap",
+      Self::Captrs => "captrs",
+      Self::Test => "test",
+    };
+    write!(f, "{string}")
+  }
+}
+
+/// Rectangle of the screen, in pixels from the top left corner
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+  pub x: usize,
+  pub y: usize,
+  pub width: usize,
+  pub height: usize,
+}
+
+impl Region {
+  /// Parse `x,y,width,height`
+  pub fn parse(s: &str) -> Result<Self, String> {
+    let numbers = s
+      .split(',')
+      .map(|n| n.trim().parse())
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(|e| format!("Invalid region `{s}`: {e}"))?;
+
+    let [x, y, width, height] = numbers[..] else {
+      return Err(format!("Expected X,Y,WIDTH,HEIGHT, got `{s}`"));
+    };
+
+    if width == 0 || height == 0 {
+      return Err(format!("The region `{s}` is empty"));
+    }
+
+    Ok(Self {
+      x,
+      y,
+      width,
+      height,
+    })
+  }
+}
+
+impl fmt::Display for Region {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+  }
+}
+
+/// Passes on only a region of the frames of another source
+///
+/// The frames aren't copied, they point into the frames of the source and keep
+/// its stride.
+pub struct Cropped {
+  source: Box<dyn CaptureSource>,
+  region: Region,
+}
+
+impl Cropped {
+  pub fn new(source: Box<dyn CaptureSource>, region: Region) -> Result<Self, String> {
+    let (width, height) = (source.width(), source.height());
+
+    if region.x + region.width > width || region.y + region.height > height {
+      return Err(format!(
+        "The region {region} doesn't fit into the {width}x{height} screen"
+      ));
+    }
+
+    Ok(Self { source, region })
+  }
+}
+
+impl CaptureSource for Cropped {
+  fn width(&self) -> usize {
+    self.region.width
+  }
+
+  fn height(&self) -> usize {
+    self.region.height
+  }
+
+  fn pixel_format(&self) -> PixelFormat {
+    self.source.pixel_format()
+  }
+
+  fn frame(&mut self) -> io::Result<Frame<'_>> {
+    let bytes_per_pixel = self.source.pixel_format().bytes_per_pixel();
+    let frame = self.source.frame()?;
+
+    // the last row ends right after the region, without the padding
+    let offset = self.region.y * frame.stride + self.region.x * bytes_per_pixel;
+    let length = (self.region.height - 1) * frame.stride + self.region.width * bytes_per_pixel;
+
+    Ok(frame.slice(offset, length))
+  }
+}
+
+pub struct ScrapSource {
+  capturer: scrap::Capturer,
+}
+
+impl ScrapSource {
+  pub fn new(display: scrap::Display) -> io::Result<Self> {
+    let capturer = scrap::Capturer::new(display)?;
+
+    Ok(Self { capturer })
+  }
+}
+
+impl CaptureSource for ScrapSource {
+  fn width(&self) -> usize {
+    self.capturer.width()
+  }
+
+  fn height(&self) -> usize {
+    self.capturer.height()
+  }
+
+  fn pixel_format(&self) -> PixelFormat {
+    PixelFormat::Bgra
+  }
+
+  fn frame(&mut self) -> io::Result<Frame<'_>> {
+    let height = self.capturer.height();
+    let frame = self.capturer.frame()?;
+
+    // scrap doesn't report the stride, but the rows may be padded
+    let stride = frame.len() / height;
+
+    Ok(Frame::scrap(frame, stride))
+  }
+}
+
+pub struct CaptrsSource {
+  capturer: captrs::Capturer,
+  width: usize,
+  height: usize,
+  buffer: Vec<u8>,
+}
+
+impl CaptrsSource {
+  pub fn new(display: usize) -> io::Result<Self> {
+    let capturer =
+      captrs::Capturer::new(display).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let (width, height) = capturer.geometry();
+
+    Ok(Self {
+      capturer,
+      width: width as usize,
+      height: height as usize,
+      buffer: Vec::new(),
+    })
+  }
+}
+
+impl CaptureSource for CaptrsSource {
+  fn width(&self) -> usize {
+    self.width
+  }
+
+  fn height(&self) -> usize {
+    self.height
+  }
+
+  fn pixel_format(&self) -> PixelFormat {
+    PixelFormat::Bgra
+  }
+
+  fn frame(&mut self) -> io::Result<Frame<'_>> {
+    match self.capturer.capture_store_frame() {
+      Ok(()) => {}
+      Err(captrs::CaptureError::Timeout) => return Err(io::ErrorKind::WouldBlock.into()),
+      Err(e) => return Err(io::Error::new(io::ErrorKind::Other, format!("{e:?}"))),
+    }
+
+    let pixels = self
+      .capturer
+      .get_stored_frame()
+      .ok_or(io::ErrorKind::WouldBlock)?;
+
+    self.buffer.clear();
+    self
+      .buffer
+      .extend(pixels.iter().flat_map(|p| [p.b, p.g, p.r, p.a]));
+
+    Ok(Frame::borrowed(
+      &self.buffer,
+      self.width * PixelFormat::Bgra.bytes_per_pixel(),
+    ))
+  }
+}
+
+/// Synthetic source of moving colour bars, so the recording can be tested
+/// without a display
+pub struct TestPattern {
+  width: usize,
+  height: usize,
+  index: usize,
+  buffer: Vec<u8>,
+}
+
+impl TestPattern {
+  /// Size of the pattern recorded by `--source test`
+  pub const SIZE: (usize, usize) = (1280, 720);
+
+  pub fn new(width: usize, height: usize) -> Self {
+    Self {
+      width,
+      height,
+      index: 0,
+      buffer: vec![0; width * height * PixelFormat::Bgra.bytes_per_pixel()],
+    }
+  }
+}
+
+impl CaptureSource for TestPattern {
+  fn width(&self) -> usize {
+    self.width
+  }
+
+  fn height(&self) -> usize {
+    self.height
+  }
+
+  fn pixel_format(&self) -> PixelFormat {
+    PixelFormat::Bgra
+  }
+
+  fn frame(&mut self) -> io::Result<Frame<'_>> {
+    const BARS: [[u8; 4]; 8] = [
+      [255, 255, 255, 255],
+      [0, 255, 255, 255],
+      [255, 255, 0, 255],
+      [0, 255, 0, 255],
+      [255, 0, 255, 255],
+      [0, 0, 255, 255],
+      [255, 0, 0, 255],
+      [0, 0, 0, 255],
+    ];
+
+    let bar_width = (self.width / BARS.len()).max(1);
+    for (y, row) in self
+      .buffer
+      .chunks_exact_mut(self.width * PixelFormat::Bgra.bytes_per_pixel())
+      .enumerate()
+    {
+      for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+        // the bars scroll to the side, with a gradient going down
+        let bar = ((x + self.index) / bar_width) % BARS.len();
+        let shade = (y * 255 / self.height) as u8;
+
+        for (out, channel) in pixel.iter_mut().zip(BARS[bar]) {
+          *out = channel.saturating_sub(shade / 2);
+        }
+      }
+    }
+
+    self.index += 1;
+
+    Ok(Frame::borrowed(
+      &self.buffer,
+      self.width * PixelFormat::Bgra.bytes_per_pixel(),
+    ))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cropped_frames_point_into_the_source() {
+    let region = Region {
+      x: 3,
+      y: 2,
+      width: 5,
+      height: 4,
+    };
+    let mut full = TestPattern::new(16, 9);
+    let mut cropped = Cropped::new(Box::new(TestPattern::new(16, 9)), region).unwrap();
+
+    let full = full.frame().unwrap();
+    let cropped = cropped.frame().unwrap();
+    assert_eq!(cropped.stride, full.stride);
+
+    for y in 0..region.height {
+      let row = &full[(region.y + y) * full.stride + region.x * 4..][..region.width * 4];
+      assert_eq!(&cropped[y * cropped.stride..][..region.width * 4], row);
+    }
+    assert_eq!(cropped.len(), 3 * full.stride + region.width * 4);
+  }
+}
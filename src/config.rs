@@ -0,0 +1,206 @@
+//! Settings file, `$XDG_CONFIG_HOME/shadowplay/config.toml`
+//!
+//! The settings are named after the long options and become their defaults,
+//! so the flags still override them. The tables in `[profiles.<NAME>]`
+//! override the top-level settings when picked with `--profile <NAME>`.
+//! Options that can be repeated take an array.
+
+use std::{collections::BTreeMap, env, fs, io, path::PathBuf};
+
+use clap::{ArgAction, Command};
+use toml::{value::Table, Value};
+
+/// Options that make no sense in the file
+const EXCLUDED: [&str; 3] = ["help", "version", "profile"];
+
+#[derive(Debug, Default)]
+pub struct Config {
+  settings: Table,
+  profiles: Table,
+}
+
+/// `$XDG_CONFIG_HOME/shadowplay/config.toml`, `~/.config` without it
+pub fn path() -> PathBuf {
+  env::var_os("XDG_CONFIG_HOME")
+    .map(PathBuf::from)
+    .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    .unwrap_or_default()
+    .join("shadowplay/config.toml")
+}
+
+impl Config {
+  /// Read the file, a missing file is an empty config
+  pub fn load() -> Result<Self, String> {
+    let path = path();
+
+    let text = match fs::read_to_string(&path) {
+      Ok(text) => text,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+      Err(e) => return Err(format!("Can't read {path:?}: {e}")),
+    };
+
+    let mut settings: Table =
+      toml::from_str(&text).map_err(|e| format!("Invalid {path:?}: {e}"))?;
+
+    let profiles = match settings.remove("profiles") {
+      Some(Value::Table(profiles)) => profiles,
+      Some(_) => return Err(format!("Invalid {path:?}: `profiles` must be a table")),
+      None => Table::new(),
+    };
+
+    Ok(Self { settings, profiles })
+  }
+
+  /// Names of the settings in the file, including all the profiles
+  fn names(&self) -> impl Iterator<Item = String> + '_ {
+    let profiles = self.profiles.values().filter_map(Value::as_table);
+
+    [&self.settings]
+      .into_iter()
+      .chain(profiles)
+      .flat_map(|table| table.keys())
+      .map(|key| key.replace('_', "-"))
+  }
+
+  /// Settings of the profile on top of the common ones, by option name
+  fn settings(&self, profile: Option<&str>) -> Result<BTreeMap<String, Vec<String>>, String> {
+    let mut tables = vec![&self.settings];
+
+    if let Some(name) = profile {
+      match self.profiles.get(name) {
+        Some(Value::Table(profile)) => tables.push(profile),
+        Some(_) => return Err(format!("The profile `{name}` must be a table")),
+        None => return Err(format!("No profile `{name}` in {:?}", path())),
+      }
+    }
+
+    let mut settings = BTreeMap::new();
+    for (key, value) in tables.into_iter().flatten() {
+      let values = match value {
+        Value::Array(values) => values.iter().map(scalar).collect(),
+        value => scalar(value).map(|value| vec![value]),
+      };
+      let values =
+        values.ok_or_else(|| format!("The setting `{key}` must be a string or a number"))?;
+
+      // both `audio-input` and `audio_input` work
+      settings.insert(key.replace('_', "-"), values);
+    }
+
+    Ok(settings)
+  }
+}
+
+/// The value as it would be written on the command line
+fn scalar(value: &Value) -> Option<String> {
+  match value {
+    Value::String(string) => Some(string.clone()),
+    Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => Some(value.to_string()),
+    _ => None,
+  }
+}
+
+/// Use the settings of the profile as the defaults of the options of every
+/// subcommand
+pub fn apply(command: Command, config: &Config, profile: Option<&str>) -> Result<Command, String> {
+  let known = options(&command);
+
+  if let Some(name) = config.names().find(|name| !known.contains_key(name)) {
+    return Err(format!("Unknown setting `{name}` in {:?}", path()));
+  }
+
+  let settings = config.settings(profile)?;
+  Ok(set_defaults(command, &settings))
+}
+
+fn set_defaults(mut command: Command, settings: &BTreeMap<String, Vec<String>>) -> Command {
+  let defaults: Vec<_> = command
+    .get_arguments()
+    .filter_map(|arg| {
+      let values = settings.get(arg.get_long()?)?;
+      Some((arg.get_id().clone(), values.clone()))
+    })
+    .collect();
+
+  for (id, values) in defaults {
+    command = command.mut_arg(id, |arg| arg.default_values(values));
+  }
+
+  let names: Vec<_> = command
+    .get_subcommands()
+    .map(|subcommand| subcommand.get_name().to_owned())
+    .collect();
+
+  for name in names {
+    command = command.mut_subcommand(name, |subcommand| set_defaults(subcommand, settings));
+  }
+
+  command
+}
+
+/// Default of an option
+enum Setting {
+  Value(String),
+  List(Vec<String>),
+  Unset,
+}
+
+/// Defaults of all the options that can be set in the file, by name
+///
+/// The options shared by several subcommands appear once. The defaults of
+/// the options that can be repeated are lists.
+fn options(command: &Command) -> BTreeMap<String, Setting> {
+  let mut found = BTreeMap::new();
+
+  for arg in command.get_arguments() {
+    let Some(long) = arg.get_long() else { continue; };
+    if EXCLUDED.contains(&long) {
+      continue;
+    }
+
+    let mut values = arg
+      .get_default_values()
+      .iter()
+      .map(|value| value.to_string_lossy().into_owned());
+
+    let default = if matches!(arg.get_action(), ArgAction::Append) {
+      Setting::List(values.collect())
+    } else {
+      values.next().map_or(Setting::Unset, Setting::Value)
+    };
+    found.entry(long.to_owned()).or_insert(default);
+  }
+
+  for subcommand in command.get_subcommands() {
+    for (long, default) in options(subcommand) {
+      found.entry(long).or_insert(default);
+    }
+  }
+
+  found
+}
+
+/// Print the settings as a config file, the unset ones commented out
+pub fn show(command: &Command) {
+  println!("# {:?}", path());
+
+  for (name, default) in options(command) {
+    match default {
+      Setting::Value(value) => println!("{name} = {}", to_toml(value)),
+      Setting::List(values) => {
+        let values: Vec<_> = values.into_iter().map(to_toml).collect();
+        println!("{name} = [{}]", values.join(", "));
+      }
+      Setting::Unset => println!("# {name} ="),
+    }
+  }
+}
+
+/// Numbers need no quotes
+fn to_toml(value: String) -> String {
+  if value.parse::<f64>().is_ok() {
+    value
+  } else {
+    Value::String(value).to_string()
+  }
+}
@@ -0,0 +1,130 @@
+//! Control socket of the daemon
+//!
+//! Every connection carries one request: the client writes the name of the
+//! command on a line, the daemon answers with a line starting with `ok` or
+//! `error`, followed by a message.
+
+use std::{
+  env, fmt, fs,
+  io::{self, BufRead, BufReader, Write},
+  os::unix::net::{UnixListener, UnixStream},
+  path::PathBuf,
+  time::Duration,
+};
+
+use clap::ValueEnum;
+
+use crate::error;
+
+/// How long a client may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Request {
+  /// Start recording
+  Start,
+  /// Stop recording and finish the file
+  Stop,
+  /// Save the replay buffer into a new file
+  SaveReplay,
+  /// Pause the recording, or resume it when paused
+  Pause,
+  /// Remember the current time of the recording
+  Bookmark,
+  /// Save the next captured frame as a PNG
+  Screenshot,
+  /// Tell whether the daemon is recording
+  Status,
+}
+
+impl fmt::Display for Request {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let string = match self {
+      Self::Start => "start",
+      Self::Stop => "stop",
+      Self::SaveReplay => "save-replay",
+      Self::Pause => "pause",
+      Self::Bookmark => "bookmark",
+      Self::Screenshot => "screenshot",
+      Self::Status => "status",
+    };
+    write!(f, "{string}")
+  }
+}
+
+/// `$XDG_RUNTIME_DIR/shadowplay.sock`, or in the temporary folder without it
+pub fn socket_path() -> PathBuf {
+  env::var_os("XDG_RUNTIME_DIR")
+    .map_or_else(env::temp_dir, PathBuf::from)
+    .join("shadowplay.sock")
+}
+
+/// Create the control socket, replacing one left behind by a crashed daemon
+pub fn bind() -> io::Result<UnixListener> {
+  let path = socket_path();
+
+  if UnixStream::connect(&path).is_ok() {
+    return Err(io::Error::new(
+      io::ErrorKind::AddrInUse,
+      format!("Another daemon is listening on {path:?}"),
+    ));
+  }
+
+  // nobody is listening, so the socket is stale if it exists
+  let _ = fs::remove_file(&path);
+
+  UnixListener::bind(&path)
+}
+
+/// Answer the requests one by one, forever
+pub fn serve(listener: &UnixListener, mut handle: impl FnMut(Request) -> Result<String, String>) {
+  for stream in listener.incoming() {
+    if let Err(e) = stream.and_then(|stream| answer(&stream, &mut handle)) {
+      error(format!("Control connection failed: {e}"));
+    }
+  }
+}
+
+fn answer(
+  stream: &UnixStream,
+  handle: &mut impl FnMut(Request) -> Result<String, String>,
+) -> io::Result<()> {
+  stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+  let mut line = String::new();
+  BufReader::new(stream).read_line(&mut line)?;
+
+  let reply = match Request::from_str(line.trim(), true) {
+    Ok(request) => handle(request),
+    Err(_) => Err(format!("Unknown request {:?}", line.trim())),
+  };
+
+  let mut stream = stream;
+  match reply {
+    Ok(message) => writeln!(stream, "ok {message}"),
+    Err(message) => writeln!(stream, "error {message}"),
+  }
+}
+
+/// Send a request to the daemon and wait for the answer
+///
+/// Both the failures to reach the daemon and its error answers end up in
+/// `Err`.
+pub fn send(request: Request) -> Result<String, String> {
+  let path = socket_path();
+  let connection_error = |e: io::Error| format!("Can't talk to the daemon at {path:?}: {e}");
+
+  let mut stream = UnixStream::connect(&path).map_err(connection_error)?;
+  writeln!(stream, "{request}").map_err(connection_error)?;
+
+  let mut reply = String::new();
+  BufReader::new(stream)
+    .read_line(&mut reply)
+    .map_err(connection_error)?;
+
+  match reply.trim_end().split_once(' ') {
+    Some(("ok", message)) => Ok(message.to_owned()),
+    Some(("error", message)) => Err(message.to_owned()),
+    _ => Err(format!("Unexpected answer from the daemon: {reply:?}")),
+  }
+}
@@ -1,122 +1,983 @@
-#[allow(dead_code)]
-pub fn argb_to_yuv420(width: usize, height: usize, src: &[u8]) -> Vec<u8> {
-  let frame_size = width * height;
-  let u_size = frame_size / 4;
+//! Conversion of captured BGRA frames into the YUV layouts of the encoder
+//!
+//! Every conversion is done row by row, using the fastest implementation of
+//! the row functions the CPU supports. The SIMD versions give exactly the
+//! same results as the scalar ones. Large frames can be split into bands
+//! converted in parallel, see [`Converter`].
+//!
+//! Any width and height work. The subsampled chroma planes are rounded up,
+//! and the 2 by 2 blocks at the right and bottom edge reuse the last column
+//! and row of the frame for their missing pixels.
 
-  let mut yuv = vec![0; frame_size * 3 / 2];
+#[cfg(target_arch = "aarch64")]
+mod neon;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86;
 
-  let mut u_index = frame_size;
-  let mut v_index = u_index + u_size;
+use std::{mem, ops, thread};
 
-  let mut column_index = 0;
-  let mut row_index = 0;
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
 
-  for (y_index, [b, g, r, _]) in src.array_chunks().enumerate() {
-    let r = i32::from(*r);
-    let g = i32::from(*g);
-    let b = i32::from(*b);
+use crate::{
+  scale::Scaler,
+  yuv::{ColorSpace, PlaneLayout, Range, YuvFrame},
+};
 
-    yuv[y_index] = clamp((66 * r + 129 * g + 25 * b + 128) / 256 + 16);
-
-    if column_index % 2 == 0 && row_index % 2 == 0 {
-      yuv[u_index] = clamp((-38 * r - 74 * g + 112 * b + 128) / 256 + 128);
-      yuv[v_index] = clamp((112 * r - 94 * g - 18 * b + 128) / 256 + 128);
+/// Integer coefficients of the conversion for one colour space and range
+///
+/// The coefficients are ordered red, green, blue and scaled by 256.
+#[derive(Debug, Clone, Copy)]
+struct Matrix {
+  y: [i32; 3],
+  u: [i32; 3],
+  v: [i32; 3],
+  /// Added to the luma, the black level
+  y_offset: i32,
+}
 
-      u_index += 1;
-      v_index += 1;
+impl Matrix {
+  fn new(colorspace: ColorSpace, range: Range) -> Self {
+    match (colorspace, range) {
+      (ColorSpace::Bt601, Range::Limited) => Self {
+        y: [66, 129, 25],
+        u: [-38, -74, 112],
+        v: [112, -94, -18],
+        y_offset: 16,
+      },
+      (ColorSpace::Bt601, Range::Full) => Self {
+        y: [77, 150, 29],
+        u: [-43, -85, 128],
+        v: [128, -107, -21],
+        y_offset: 0,
+      },
+      (ColorSpace::Bt709, Range::Limited) => Self {
+        y: [47, 157, 16],
+        u: [-26, -86, 112],
+        v: [112, -102, -10],
+        y_offset: 16,
+      },
+      (ColorSpace::Bt709, Range::Full) => Self {
+        y: [54, 183, 19],
+        u: [-29, -99, 128],
+        v: [128, -116, -12],
+        y_offset: 0,
+      },
     }
+  }
+
+  fn y(&self, [r, g, b]: [i32; 3]) -> i32 {
+    let [y_r, y_g, y_b] = self.y;
+    (y_r * r + y_g * g + y_b * b + 128) / 256 + self.y_offset
+  }
+
+  fn u(&self, [r, g, b]: [i32; 3]) -> i32 {
+    let [u_r, u_g, u_b] = self.u;
+    (u_r * r + u_g * g + u_b * b + 128) / 256 + 128
+  }
+
+  fn v(&self, [r, g, b]: [i32; 3]) -> i32 {
+    let [v_r, v_g, v_b] = self.v;
+    (v_r * r + v_g * g + v_b * b + 128) / 256 + 128
+  }
+}
+
+/// Converts a row of pixels into a row of a single plane
+type PlaneRow = unsafe fn(&Matrix, &[u8], &mut [u8]);
+/// Converts a row of pixels into a row of the U and V planes
+type ChromaRow = unsafe fn(&Matrix, &[u8], &mut [u8], &mut [u8]);
+/// Converts two rows of pixels into a row of the U and V planes
+type ChromaRows = unsafe fn(&Matrix, &[u8], &[u8], &mut [u8], &mut [u8]);
+
+/// Conversions of a single row, implemented for one instruction set
+#[derive(Clone, Copy)]
+struct Kernels {
+  /// Luma of every pixel of the row
+  y: PlaneRow,
+  /// Chroma of every pixel of the row
+  uv: ChromaRow,
+  /// Chroma of every other pixel of the row
+  uv_sampled: ChromaRow,
+  /// Chroma averaged over 2 by 2 blocks of two rows
+  uv_averaged: ChromaRows,
+}
 
-    column_index += 1;
+const SCALAR: Kernels = Kernels {
+  y: y_row,
+  uv: uv_row,
+  uv_sampled: uv_row_sampled,
+  uv_averaged: uv_row_averaged,
+};
 
-    if column_index == width {
-      row_index += 1;
-      column_index = 0;
+/// Pick the fastest row functions this CPU can run
+fn kernels() -> Kernels {
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  {
+    if is_x86_feature_detected!("avx2") {
+      return x86::AVX2;
+    }
+    if is_x86_feature_detected!("sse2") {
+      return x86::SSE2;
     }
   }
 
-  yuv
+  #[cfg(target_arch = "aarch64")]
+  if std::arch::is_aarch64_feature_detected!("neon") {
+    return neon::NEON;
+  }
+
+  SCALAR
 }
-#[allow(dead_code)]
-pub fn argb_to_yuv420_with_subsampling(width: usize, height: usize, src: &[u8]) -> Vec<u8> {
-  let frame_size = width * height;
-  let u_size = frame_size / 4;
 
-  let mut yuv = vec![0; frame_size * 3 / 2];
+/// Threads to convert with when not set, leaving the rest of the cores to
+/// the capture and the encoder
+pub fn default_threads() -> usize {
+  thread::available_parallelism().map_or(1, |cores| (cores.get() / 2).max(1))
+}
 
-  let mut y_index = 0;
-  let mut u_index = frame_size;
-  let mut v_index = u_index + u_size;
+/// Converts frames using the fastest row functions the CPU supports
+///
+/// With more than one thread the frame is split into horizontal bands, each
+/// starting at an even row so it has its own rows of subsampled chroma, and
+/// the bands are converted on a pool of workers. With a [`Scaler`] every row
+/// is scaled right before it's converted, so the frame is walked only once.
+pub struct Converter {
+  kernels: Kernels,
+  matrix: Matrix,
+  scaler: Option<Scaler>,
+  pool: Option<ThreadPool>,
+  threads: usize,
+}
 
-  let get_pixel_idx = |idx| {
-    let r = i32::from(src[idx + 2]);
-    let g = i32::from(src[idx + 1]);
-    let b = i32::from(src[idx]);
+impl Converter {
+  pub fn new(
+    threads: usize,
+    colorspace: ColorSpace,
+    range: Range,
+    scaler: Option<Scaler>,
+  ) -> Result<Self, ThreadPoolBuildError> {
+    let pool = (threads > 1)
+      .then(|| {
+        ThreadPoolBuilder::new()
+          .num_threads(threads)
+          .thread_name(|index| format!("convert-{index}"))
+          .build()
+      })
+      .transpose()?;
 
-    [r, g, b]
-  };
+    Ok(Self {
+      kernels: kernels(),
+      matrix: Matrix::new(colorspace, range),
+      scaler,
+      pool,
+      threads: threads.max(1),
+    })
+  }
 
-  let get_pixel = |x, y| get_pixel_idx((x + y * width) * 4);
+  /// The conversions take the size of the converted frame, which is also the
+  /// size of `src` unless it's scaled, and the length of the rows of `src`
+  ///
+  /// The `_into` versions convert into the planes of a frame of that size
+  /// instead, which may be padded. They panic if the frame has another
+  /// layout.
+  #[allow(dead_code)]
+  pub fn argb_to_yuv420(&self, width: usize, height: usize, src: &[u8], stride: usize) -> YuvFrame {
+    let mut yuv = YuvFrame::new(PlaneLayout::I420, width, height);
+    self.argb_to_yuv420_into(src, stride, &mut yuv);
+    yuv
+  }
 
-  let calc_y = |[r, g, b]: [i32; 3]| clamp((66 * r + 129 * g + 25 * b + 128) / 256 + 16);
-  let calc_u = |[r, g, b]: [i32; 3]| (-38 * r - 74 * g + 112 * b + 128) / 256 + 128;
-  let calc_v = |[r, g, b]: [i32; 3]| (112 * r - 94 * g - 18 * b + 128) / 256 + 128;
+  #[allow(dead_code)]
+  pub fn argb_to_yuv420_into(&self, src: &[u8], stride: usize, yuv: &mut YuvFrame) {
+    let (width, height) = (yuv.width(), yuv.height());
+    let kernels = self.kernels;
+    let matrix = &self.matrix;
+    let (chroma_width, _) = PlaneLayout::I420.chroma_size(width, height);
 
-  for y in 0..height {
-    for x in 0..width {
-      let pixel = get_pixel(x, y);
+    self.convert(PlaneLayout::I420, src, stride, yuv, |pair| {
+      let Pair {
+        top,
+        bottom,
+        planes: [y, u, v],
+        strides,
+      } = pair;
+      convert_luma(kernels, matrix, width, [Some(top), bottom], y, strides[0]);
 
-      yuv[y_index] = calc_y(pixel);
+      // the chroma is taken from the top left pixel of every 2 by 2 block
+      let (u, v) = (&mut u[..chroma_width], &mut v[..chroma_width]);
+      // SAFETY: only the row functions supported by the CPU are ever picked
+      unsafe { (kernels.uv_sampled)(matrix, top, u, v) };
+    });
+  }
 
-      y_index += 1;
+  #[allow(dead_code)]
+  pub fn argb_to_yuv420_with_subsampling(
+    &self,
+    width: usize,
+    height: usize,
+    src: &[u8],
+    stride: usize,
+  ) -> YuvFrame {
+    let mut yuv = YuvFrame::new(PlaneLayout::I420, width, height);
+    self.argb_to_yuv420_with_subsampling_into(src, stride, &mut yuv);
+    yuv
+  }
 
-      if x % 2 == 0 && y % 2 == 0 {
-        // use subsampling for every 2 by 2 block
-        let sample = [
-          pixel,
-          get_pixel(x + 1, y),
-          get_pixel(x, y + 1),
-          get_pixel(x + 1, y + 1),
-        ];
+  #[allow(dead_code)]
+  pub fn argb_to_yuv420_with_subsampling_into(
+    &self,
+    src: &[u8],
+    stride: usize,
+    yuv: &mut YuvFrame,
+  ) {
+    let (width, height) = (yuv.width(), yuv.height());
+    let kernels = self.kernels;
+    let matrix = &self.matrix;
+    let (chroma_width, _) = PlaneLayout::I420.chroma_size(width, height);
 
-        // average the values
-        let u = sample.into_iter().map(calc_u).sum::<i32>() / 4;
-        let v = sample.into_iter().map(calc_v).sum::<i32>() / 4;
+    self.convert(PlaneLayout::I420, src, stride, yuv, |pair| {
+      let Pair {
+        top,
+        bottom,
+        planes: [y, u, v],
+        strides,
+      } = pair;
+      convert_luma(kernels, matrix, width, [Some(top), bottom], y, strides[0]);
 
-        yuv[u_index] = clamp(u);
-        yuv[v_index] = clamp(v);
+      // use subsampling for every 2 by 2 block, the last odd row is
+      // averaged with itself
+      let (u, v) = (&mut u[..chroma_width], &mut v[..chroma_width]);
+      // SAFETY: only the row functions supported by the CPU are ever picked
+      unsafe { (kernels.uv_averaged)(matrix, top, bottom.unwrap_or(top), u, v) };
+    });
+  }
+
+  #[allow(dead_code)]
+  pub fn argb_to_yuv444(&self, width: usize, height: usize, src: &[u8], stride: usize) -> YuvFrame {
+    let mut yuv = YuvFrame::new(PlaneLayout::I444, width, height);
+    self.argb_to_yuv444_into(src, stride, &mut yuv);
+    yuv
+  }
+
+  #[allow(dead_code)]
+  pub fn argb_to_yuv444_into(&self, src: &[u8], stride: usize, yuv: &mut YuvFrame) {
+    let width = yuv.width();
+    let kernels = self.kernels;
+    let matrix = &self.matrix;
+
+    self.convert(PlaneLayout::I444, src, stride, yuv, |pair| {
+      let Pair {
+        top,
+        bottom,
+        planes: [y, u_plane, v_plane],
+        strides,
+      } = pair;
+      convert_luma(kernels, matrix, width, [Some(top), bottom], y, strides[0]);
+
+      let rows = [Some(top), bottom].into_iter().flatten();
+      let u_rows = u_plane.chunks_mut(strides[1]);
+      let v_rows = v_plane.chunks_mut(strides[2]);
+
+      for ((row, u), v) in rows.zip(u_rows).zip(v_rows) {
+        // SAFETY: only the row functions supported by the CPU are ever picked
+        unsafe { (kernels.uv)(matrix, row, &mut u[..width], &mut v[..width]) };
+      }
+    });
+  }
+
+  #[allow(dead_code)]
+  pub fn argb_to_nv12(&self, width: usize, height: usize, src: &[u8], stride: usize) -> YuvFrame {
+    let mut yuv = YuvFrame::new(PlaneLayout::NV12, width, height);
+    self.argb_to_nv12_into(src, stride, &mut yuv);
+    yuv
+  }
+
+  #[allow(dead_code)]
+  pub fn argb_to_nv12_into(&self, src: &[u8], stride: usize, yuv: &mut YuvFrame) {
+    let (width, height) = (yuv.width(), yuv.height());
+    let kernels = self.kernels;
+    let matrix = &self.matrix;
+    let (chroma_width, _) = PlaneLayout::NV12.chroma_size(width, height);
 
-        u_index += 1;
-        v_index += 1;
+    self.convert(PlaneLayout::NV12, src, stride, yuv, |pair| {
+      let Pair {
+        top,
+        bottom,
+        planes: [y, uv_plane, _],
+        strides,
+      } = pair;
+      convert_luma(kernels, matrix, width, [Some(top), bottom], y, strides[0]);
+
+      // the row functions write separate planes, which are then interleaved
+      let mut u = vec![0; chroma_width];
+      let mut v = vec![0; chroma_width];
+
+      // SAFETY: only the row functions supported by the CPU are ever picked
+      unsafe { (kernels.uv_sampled)(matrix, top, &mut u, &mut v) };
+
+      for ((uv, u), v) in uv_plane.chunks_exact_mut(2).zip(&u).zip(&v) {
+        uv[0] = *u;
+        uv[1] = *v;
       }
+    });
+  }
+
+  /// Split the frame into bands and convert them pair of rows by pair of rows
+  ///
+  /// A frame too short for its size and stride is left as it is.
+  fn convert<F>(
+    &self,
+    layout: PlaneLayout,
+    src: &[u8],
+    stride: usize,
+    yuv: &mut YuvFrame,
+    convert: F,
+  ) where
+    F: Fn(Pair<'_>) + Sync,
+  {
+    assert_eq!(
+      yuv.layout(),
+      layout,
+      "can't convert into a {} frame",
+      yuv.layout()
+    );
+    let (width, height) = (yuv.width(), yuv.height());
+    let strides = yuv.strides();
+
+    let source = Source {
+      data: src,
+      width,
+      stride,
+      scaler: self.scaler.as_ref(),
+    };
+    if src.len() < source.len(height) {
+      return;
     }
+
+    // the parts of the planes for a number of rows starting at an even row
+    let lengths = |rows| {
+      let (_, chroma_rows) = layout.chroma_size(width, rows);
+      [
+        rows * strides[0],
+        chroma_rows * strides[1],
+        chroma_rows * strides[2],
+      ]
+    };
+
+    // round up to whole pairs of rows
+    let band_height = ((height + self.threads - 1) / self.threads + 1) / 2 * 2;
+
+    let mut bands = Vec::with_capacity(self.threads);
+    let mut planes = yuv.planes_mut();
+    let mut row = 0;
+
+    while row < height {
+      let rows = band_height.min(height - row);
+      bands.push((row..row + rows, split_planes(&mut planes, lengths(rows))));
+      row += rows;
+    }
+
+    let convert_band = |(rows, mut planes): (ops::Range<usize>, [&mut [u8]; 3])| {
+      // only used when scaling
+      let (mut top_buffer, mut bottom_buffer) = (Vec::new(), Vec::new());
+
+      for y in rows.clone().step_by(2) {
+        let count = (rows.end - y).min(2);
+
+        convert(Pair {
+          top: source.row(y, &mut top_buffer),
+          bottom: (count == 2).then(|| source.row(y + 1, &mut bottom_buffer)),
+          planes: split_planes(&mut planes, lengths(count)),
+          strides,
+        });
+      }
+    };
+
+    match &self.pool {
+      Some(pool) => pool.install(|| bands.into_par_iter().for_each(convert_band)),
+      None => bands.into_iter().for_each(convert_band),
+    }
+  }
+}
+
+/// Two rows of pixels and the parts of the planes they are converted into
+struct Pair<'a> {
+  top: &'a [u8],
+  /// `None` when the frame ends with an odd row
+  bottom: Option<&'a [u8]>,
+  planes: [&'a mut [u8]; 3],
+  /// Length of a row of each plane, taken from the [`YuvFrame`], may be more
+  /// than the samples in it
+  strides: [usize; 3],
+}
+
+/// Rows of a captured frame in the size of the converted frame
+struct Source<'a> {
+  data: &'a [u8],
+  width: usize,
+  /// Length of the rows in `data`
+  stride: usize,
+  scaler: Option<&'a Scaler>,
+}
+
+impl Source<'_> {
+  /// Bytes needed for a converted frame of the given height, the last row
+  /// needs no padding
+  fn len(&self, height: usize) -> usize {
+    let (width, height) = self
+      .scaler
+      .map_or((self.width, height), Scaler::source_size);
+
+    height.saturating_sub(1) * self.stride + width * 4
   }
 
-  yuv
+  /// Pixels of the row, scaled into the buffer if needed
+  fn row<'a>(&'a self, y: usize, buffer: &'a mut Vec<u8>) -> &'a [u8] {
+    let length = self.width * 4;
+
+    let Some(scaler) = self.scaler else { return &self.data[y * self.stride..][..length]; };
+
+    buffer.resize(length, 0);
+    scaler.scale_row(self.data, self.stride, y, buffer);
+    buffer
+  }
 }
 
-#[allow(dead_code)]
-pub fn argb_to_yuv444(width: usize, height: usize, src: &[u8]) -> Vec<u8> {
-  let frame_size = width * height;
+/// Split the given lengths off the starts of the planes
+fn split_planes<'a>(planes: &mut [&'a mut [u8]; 3], lengths: [usize; 3]) -> [&'a mut [u8]; 3] {
+  let mut split = |index: usize| {
+    let (band, rest) = mem::take(&mut planes[index]).split_at_mut(lengths[index]);
+    planes[index] = rest;
+    band
+  };
 
-  let mut yuv = vec![0; frame_size * 3];
+  [split(0), split(1), split(2)]
+}
 
-  let u_offset = frame_size;
-  let v_offset = u_offset + frame_size;
+/// Fill the one or two rows of the Y plane
+fn convert_luma(
+  kernels: Kernels,
+  matrix: &Matrix,
+  width: usize,
+  rows: [Option<&[u8]>; 2],
+  y_plane: &mut [u8],
+  y_stride: usize,
+) {
+  let rows = rows.into_iter().flatten();
 
-  for (y_index, [b, g, r, _]) in src.array_chunks().enumerate() {
-    let r = i32::from(*r);
-    let g = i32::from(*g);
-    let b = i32::from(*b);
+  for (row, y) in rows.zip(y_plane.chunks_mut(y_stride)) {
+    // SAFETY: only the row functions supported by the CPU are ever picked
+    unsafe { (kernels.y)(matrix, row, &mut y[..width]) };
+  }
+}
 
-    yuv[y_index] = clamp((66 * r + 129 * g + 25 * b + 128) / 256 + 16);
-    yuv[y_index + u_offset] = clamp((-38 * r - 74 * g + 112 * b + 128) / 256 + 128);
-    yuv[y_index + v_offset] = clamp((112 * r - 94 * g - 18 * b + 128) / 256 + 128);
+fn y_row(matrix: &Matrix, src: &[u8], y: &mut [u8]) {
+  for (pixel, y) in src.chunks_exact(4).zip(y) {
+    *y = clamp(matrix.y(rgb(pixel)));
   }
+}
 
-  yuv
+fn uv_row(matrix: &Matrix, src: &[u8], u: &mut [u8], v: &mut [u8]) {
+  for ((pixel, u), v) in src.chunks_exact(4).zip(u).zip(v) {
+    let pixel = rgb(pixel);
+    *u = clamp(matrix.u(pixel));
+    *v = clamp(matrix.v(pixel));
+  }
+}
+
+fn uv_row_sampled(matrix: &Matrix, src: &[u8], u: &mut [u8], v: &mut [u8]) {
+  // the last block of an odd row has just one pixel
+  for ((pixels, u), v) in src.chunks(8).zip(u).zip(v) {
+    let pixel = rgb(pixels);
+    *u = clamp(matrix.u(pixel));
+    *v = clamp(matrix.v(pixel));
+  }
+}
+
+fn uv_row_averaged(matrix: &Matrix, top: &[u8], bottom: &[u8], u: &mut [u8], v: &mut [u8]) {
+  for (((top, bottom), u), v) in top.chunks(8).zip(bottom.chunks(8)).zip(u).zip(v) {
+    // the last pixel of an odd row stands in for its missing neighbour
+    let right = top.len() - 4;
+    let sample = [
+      rgb(top),
+      rgb(&top[right..]),
+      rgb(bottom),
+      rgb(&bottom[right..]),
+    ];
+
+    // average the values, each clamped on its own like in the SIMD versions
+    let average = |calc: fn(&Matrix, [i32; 3]) -> i32| {
+      let sum: i32 = sample
+        .into_iter()
+        .map(|pixel| i32::from(clamp(calc(matrix, pixel))))
+        .sum();
+      clamp(sum / 4)
+    };
+
+    *u = average(Matrix::u);
+    *v = average(Matrix::v);
+  }
+}
+
+/// Red, green and blue channel of the BGRA pixel at the start of the slice
+fn rgb(pixel: &[u8]) -> [i32; 3] {
+  [pixel[2], pixel[1], pixel[0]].map(i32::from)
 }
 
-#[allow(dead_code)]
 fn clamp(x: i32) -> u8 {
   x.min(255).max(0) as u8
 }
+
+#[cfg(test)]
+mod tests {
+  use std::time::Instant;
+
+  use super::*;
+  use crate::scale::Filter;
+
+  const MATRICES: [(ColorSpace, Range); 4] = [
+    (ColorSpace::Bt601, Range::Limited),
+    (ColorSpace::Bt601, Range::Full),
+    (ColorSpace::Bt709, Range::Limited),
+    (ColorSpace::Bt709, Range::Full),
+  ];
+
+  /// Deterministic xorshift noise, so a failure can be reproduced
+  struct Noise(u64);
+
+  impl Noise {
+    fn bytes(&mut self, length: usize) -> Vec<u8> {
+      (0..length)
+        .map(|_| {
+          self.0 ^= self.0 << 13;
+          self.0 ^= self.0 >> 7;
+          self.0 ^= self.0 << 17;
+          self.0 as u8
+        })
+        .collect()
+    }
+  }
+
+  /// Every set of kernels [`kernels`] can pick on this CPU
+  fn supported() -> Vec<(&'static str, Kernels)> {
+    let mut supported = vec![("scalar", SCALAR)];
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+      if is_x86_feature_detected!("sse2") {
+        supported.push(("sse2", x86::SSE2));
+      }
+      if is_x86_feature_detected!("avx2") {
+        supported.push(("avx2", x86::AVX2));
+      }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("neon") {
+      supported.push(("neon", neon::NEON));
+    }
+
+    supported
+  }
+
+  /// Outputs of all the row functions for two rows of `width` pixels
+  fn rows(kernels: Kernels, matrix: &Matrix, top: &[u8], bottom: &[u8]) -> [Vec<u8>; 7] {
+    let width = top.len() / 4;
+    let chroma_width = (width + 1) / 2;
+
+    let mut y = vec![0; width];
+    let (mut u, mut v) = (vec![0; width], vec![0; width]);
+    let (mut u_sampled, mut v_sampled) = (vec![0; chroma_width], vec![0; chroma_width]);
+    let (mut u_averaged, mut v_averaged) = (vec![0; chroma_width], vec![0; chroma_width]);
+
+    unsafe {
+      (kernels.y)(matrix, top, &mut y);
+      (kernels.uv)(matrix, top, &mut u, &mut v);
+      (kernels.uv_sampled)(matrix, top, &mut u_sampled, &mut v_sampled);
+      (kernels.uv_averaged)(matrix, top, bottom, &mut u_averaged, &mut v_averaged);
+    }
+
+    [y, u, v, u_sampled, v_sampled, u_averaged, v_averaged]
+  }
+
+  #[test]
+  fn kernels_match_scalar() {
+    let mut noise = Noise(0x2545_f491_4f6c_dd1d);
+
+    // every tail length of the 8, 16 and 32 pixel blocks, and a full HD row
+    let widths = (1..=80).chain([1919, 1920]);
+
+    for width in widths {
+      let top = noise.bytes(width * 4);
+      let bottom = noise.bytes(width * 4);
+
+      for (colorspace, range) in MATRICES {
+        let matrix = Matrix::new(colorspace, range);
+        let expected = rows(SCALAR, &matrix, &top, &bottom);
+
+        for (name, kernels) in supported() {
+          assert_eq!(
+            rows(kernels, &matrix, &top, &bottom),
+            expected,
+            "{name} differs at width {width}, {colorspace} {range}"
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn kernels_match_scalar_on_extremes() {
+    // black, white and saturated colours push the sums to their limits
+    let colours = [
+      [0, 0, 0, 255],
+      [255, 255, 255, 255],
+      [255, 0, 0, 255],
+      [0, 255, 0, 255],
+      [0, 0, 255, 255],
+      [255, 255, 0, 255],
+      [0, 255, 255, 255],
+      [255, 0, 255, 255],
+    ];
+    let top: Vec<u8> = colours.iter().cycle().take(67).flatten().copied().collect();
+    let bottom: Vec<u8> = colours
+      .iter()
+      .rev()
+      .cycle()
+      .take(67)
+      .flatten()
+      .copied()
+      .collect();
+
+    for (colorspace, range) in MATRICES {
+      let matrix = Matrix::new(colorspace, range);
+      let expected = rows(SCALAR, &matrix, &top, &bottom);
+
+      for (name, kernels) in supported() {
+        assert_eq!(
+          rows(kernels, &matrix, &top, &bottom),
+          expected,
+          "{name} differs, {colorspace} {range}"
+        );
+      }
+    }
+  }
+
+  /// Pixel of a packed BGRA frame, the edges repeat past the last row and
+  /// column
+  fn pixel(src: &[u8], width: usize, height: usize, x: usize, y: usize) -> [i32; 3] {
+    let (x, y) = (x.min(width - 1), y.min(height - 1));
+    rgb(&src[(y * width + x) * 4..])
+  }
+
+  /// The planes computed pixel by pixel, the way the conversions are specified
+  fn reference(
+    layout: PlaneLayout,
+    averaged: bool,
+    src: &[u8],
+    width: usize,
+    height: usize,
+  ) -> [Vec<u8>; 3] {
+    let matrix = Matrix::new(ColorSpace::Bt709, Range::Limited);
+    let pixel = |x, y| pixel(src, width, height, x, y);
+
+    let mut y_plane = Vec::new();
+    for y in 0..height {
+      for x in 0..width {
+        y_plane.push(clamp(matrix.y(pixel(x, y))));
+      }
+    }
+
+    let (chroma_width, chroma_height) = layout.chroma_size(width, height);
+    let step = if layout == PlaneLayout::I444 { 1 } else { 2 };
+    let (mut u_plane, mut v_plane) = (Vec::new(), Vec::new());
+
+    for y in (0..chroma_height).map(|y| y * step) {
+      for x in (0..chroma_width).map(|x| x * step) {
+        let chroma = |calc: fn(&Matrix, [i32; 3]) -> i32| {
+          if averaged {
+            let block = [
+              pixel(x, y),
+              pixel(x + 1, y),
+              pixel(x, y + 1),
+              pixel(x + 1, y + 1),
+            ];
+            let sum: i32 = block
+              .map(|pixel| i32::from(clamp(calc(&matrix, pixel))))
+              .iter()
+              .sum();
+            clamp(sum / 4)
+          } else {
+            clamp(calc(&matrix, pixel(x, y)))
+          }
+        };
+
+        u_plane.push(chroma(Matrix::u));
+        v_plane.push(chroma(Matrix::v));
+      }
+    }
+
+    if layout == PlaneLayout::NV12 {
+      let uv = u_plane
+        .iter()
+        .zip(&v_plane)
+        .flat_map(|(&u, &v)| [u, v])
+        .collect();
+      return [y_plane, uv, Vec::new()];
+    }
+
+    [y_plane, u_plane, v_plane]
+  }
+
+  #[test]
+  fn odd_sizes() {
+    let conversions: [(PlaneLayout, bool, Conversion); 4] = [
+      (PlaneLayout::I420, false, Converter::argb_to_yuv420),
+      (
+        PlaneLayout::I420,
+        true,
+        Converter::argb_to_yuv420_with_subsampling,
+      ),
+      (PlaneLayout::I444, false, Converter::argb_to_yuv444),
+      (PlaneLayout::NV12, false, Converter::argb_to_nv12),
+    ];
+    let mut noise = Noise(7);
+
+    for (width, height) in [(1, 1), (3, 3), (1919, 1079)] {
+      let src = noise.bytes(width * height * 4);
+
+      // one band, and bands ending on odd rows
+      for threads in [1, 3] {
+        let converter = Converter::new(threads, ColorSpace::Bt709, Range::Limited, None).unwrap();
+
+        for (layout, averaged, convert) in conversions {
+          let frame = convert(&converter, width, height, &src, width * 4);
+          let expected = reference(layout, averaged, &src, width, height);
+
+          assert_eq!((frame.width(), frame.height()), (width, height));
+          assert_eq!(
+            frame.planes().map(<[u8]>::to_vec),
+            expected,
+            "{layout} averaged: {averaged} differs at {width}x{height} on {threads} threads"
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn odd_plane_sizes() {
+    for (width, height) in [(1, 1), (3, 3), (1919, 1079)] {
+      let (chroma_width, chroma_height) = ((width + 1) / 2, (height + 1) / 2);
+
+      let frame = YuvFrame::new(PlaneLayout::I420, width, height);
+      let [y, u, v] = frame.planes();
+      assert_eq!(
+        [y.len(), u.len(), v.len()],
+        [
+          width * height,
+          chroma_width * chroma_height,
+          chroma_width * chroma_height
+        ]
+      );
+      assert_eq!(frame.strides(), [width, chroma_width, chroma_width]);
+    }
+  }
+
+  /// The frame with each row followed by `padding` bytes of noise
+  fn padded(src: &[u8], width: usize, padding: usize, noise: &mut Noise) -> Vec<u8> {
+    src
+      .chunks(width * 4)
+      .flat_map(|row| [row.to_vec(), noise.bytes(padding)])
+      .flatten()
+      .collect()
+  }
+
+  #[test]
+  fn padded_rows() {
+    let conversions: [Conversion; 4] = [
+      Converter::argb_to_yuv420,
+      Converter::argb_to_yuv420_with_subsampling,
+      Converter::argb_to_yuv444,
+      Converter::argb_to_nv12,
+    ];
+    let mut noise = Noise(3);
+
+    for (width, height) in [(1, 1), (3, 3), (64, 36), (1919, 1079)] {
+      let src = noise.bytes(width * height * 4);
+
+      // padded to a multiple of 64 bytes, and by a few odd bytes
+      for padding in [(64 - width * 4 % 64) % 64 + 64, 3] {
+        let stride = width * 4 + padding;
+        let padded = padded(&src, width, padding, &mut noise);
+        // the last row needs no padding
+        let padded = &padded[..padded.len() - padding];
+        let converter = Converter::new(2, ColorSpace::Bt601, Range::Full, None).unwrap();
+
+        for convert in conversions {
+          assert_eq!(
+            convert(&converter, width, height, padded, stride).data(),
+            convert(&converter, width, height, &src, width * 4).data(),
+            "stride {stride} differs at {width}x{height}"
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn padded_rows_scaled() {
+    let mut noise = Noise(5);
+    let (width, height) = (333, 185);
+    let src = noise.bytes(width * height * 4);
+    let padded = padded(&src, width, 12, &mut noise);
+
+    for filter in [Filter::Nearest, Filter::Bilinear, Filter::Area] {
+      let converter =
+        |scaler| Converter::new(1, ColorSpace::Bt709, Range::Limited, Some(scaler)).unwrap();
+      let scaled = converter(Scaler::new(filter, (width, height), (160, 91)));
+
+      assert_eq!(
+        scaled
+          .argb_to_yuv420(160, 91, &padded, width * 4 + 12)
+          .data(),
+        scaled.argb_to_yuv420(160, 91, &src, width * 4).data(),
+        "{filter} differs"
+      );
+    }
+  }
+
+  /// The samples of the planes without the padding of the rows
+  fn samples(frame: &YuvFrame) -> [Vec<u8>; 3] {
+    let (width, height) = (frame.width(), frame.height());
+    let packed = frame.layout().packed_strides(width, height);
+    let (_, chroma_height) = frame.layout().chroma_size(width, height);
+    let mut planes = frame.planes().into_iter().zip(frame.strides()).zip(packed);
+
+    [height, chroma_height, chroma_height].map(|rows| {
+      let ((plane, stride), packed) = planes.next().unwrap();
+      (0..rows)
+        .flat_map(|row| &plane[row * stride..][..packed])
+        .copied()
+        .collect()
+    })
+  }
+
+  /// Strides aligned to 32 bytes like libvpx does, and padded by a few odd
+  /// bytes, NV12 keeps the third one zero
+  fn padded_strides(layout: PlaneLayout, width: usize, height: usize) -> [[usize; 3]; 2] {
+    let packed = layout.packed_strides(width, height);
+    let pad = |padding: fn(usize) -> usize| packed.map(|s| if s == 0 { 0 } else { padding(s) });
+
+    [pad(|s| (s + 31) / 32 * 32), pad(|s| s + 3)]
+  }
+
+  #[test]
+  fn padded_planes() {
+    let conversions: [(PlaneLayout, Conversion, ConversionInto); 4] = [
+      (
+        PlaneLayout::I420,
+        Converter::argb_to_yuv420,
+        Converter::argb_to_yuv420_into,
+      ),
+      (
+        PlaneLayout::I420,
+        Converter::argb_to_yuv420_with_subsampling,
+        Converter::argb_to_yuv420_with_subsampling_into,
+      ),
+      (
+        PlaneLayout::I444,
+        Converter::argb_to_yuv444,
+        Converter::argb_to_yuv444_into,
+      ),
+      (
+        PlaneLayout::NV12,
+        Converter::argb_to_nv12,
+        Converter::argb_to_nv12_into,
+      ),
+    ];
+    let mut noise = Noise(9);
+
+    for (width, height) in [(1, 1), (3, 3), (64, 36), (1919, 1079)] {
+      let src = noise.bytes(width * height * 4);
+      let converter = Converter::new(2, ColorSpace::Bt601, Range::Full, None).unwrap();
+
+      for (layout, convert, convert_into) in conversions {
+        let expected = samples(&convert(&converter, width, height, &src, width * 4));
+
+        for strides in padded_strides(layout, width, height) {
+          let mut frame = YuvFrame::with_strides(layout, width, height, strides);
+          convert_into(&converter, &src, width * 4, &mut frame);
+
+          assert_eq!(
+            samples(&frame),
+            expected,
+            "{layout} strides {strides:?} differ at {width}x{height}"
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn padded_planes_scaled() {
+    let mut noise = Noise(11);
+    let (width, height) = (333, 185);
+    let src = noise.bytes(width * height * 4);
+
+    for filter in [Filter::Nearest, Filter::Bilinear, Filter::Area] {
+      let scaler = Scaler::new(filter, (width, height), (160, 91));
+      let converter = Converter::new(1, ColorSpace::Bt709, Range::Limited, Some(scaler)).unwrap();
+      let expected = samples(&converter.argb_to_yuv420(160, 91, &src, width * 4));
+
+      for strides in padded_strides(PlaneLayout::I420, 160, 91) {
+        let mut frame = YuvFrame::with_strides(PlaneLayout::I420, 160, 91, strides);
+        converter.argb_to_yuv420_into(&src, width * 4, &mut frame);
+
+        assert_eq!(
+          samples(&frame),
+          expected,
+          "{filter} strides {strides:?} differ"
+        );
+      }
+    }
+  }
+
+  type Conversion = fn(&Converter, usize, usize, &[u8], usize) -> YuvFrame;
+  type ConversionInto = fn(&Converter, &[u8], usize, &mut YuvFrame);
+
+  /// Time the conversions with every kernel set the CPU supports on one
+  /// thread
+  ///
+  /// It's ignored by default, run it with
+  /// `cargo test --release bench_kernels -- --ignored --nocapture`.
+  #[test]
+  #[ignore = "slow, only prints the timings"]
+  fn bench_kernels() {
+    const RUNS: u32 = 20;
+
+    for (width, height) in [(1920, 1080), (3840, 2160)] {
+      let frame = Noise(1).bytes(width * height * 4);
+
+      for (name, kernels) in supported() {
+        let mut converter = Converter::new(1, ColorSpace::Bt709, Range::Limited, None).unwrap();
+        converter.kernels = kernels;
+
+        let conversions: [(&str, Conversion); 3] = [
+          ("I420", Converter::argb_to_yuv420),
+          ("I420 averaged", Converter::argb_to_yuv420_with_subsampling),
+          ("I444", Converter::argb_to_yuv444),
+        ];
+
+        for (conversion, convert) in conversions {
+          let start = Instant::now();
+          for _ in 0..RUNS {
+            convert(&converter, width, height, &frame, width * 4);
+          }
+          let time = start.elapsed() / RUNS;
+
+          println!("{width}x{height} {conversion} {name}: {time:?}");
+        }
+      }
+    }
+  }
+}
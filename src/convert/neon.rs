@@ -0,0 +1,193 @@
+//! NEON versions of the row conversions
+//!
+//! Luma is computed in unsigned and chroma in signed 16-bit lanes. All the
+//! results fit into 16 bits, so they are the same as with the scalar code.
+
+#[allow(clippy::wildcard_imports)]
+use std::arch::aarch64::*;
+
+use super::{Kernels, Matrix};
+
+pub(super) const NEON: Kernels = Kernels {
+  y: y_row_neon,
+  uv: uv_row_neon,
+  uv_sampled: uv_row_sampled_neon,
+  uv_averaged: uv_row_averaged_neon,
+};
+
+#[target_feature(enable = "neon")]
+unsafe fn y_row_neon(matrix: &Matrix, src: &[u8], y: &mut [u8]) {
+  let blocks = y.len() / 8;
+
+  for (pixels, y) in src.chunks_exact(32).zip(y.chunks_exact_mut(8)) {
+    vst1_u8(y.as_mut_ptr(), luma_neon(matrix, load_neon(pixels)));
+  }
+
+  super::y_row(matrix, &src[blocks * 32..], &mut y[blocks * 8..]);
+}
+
+#[target_feature(enable = "neon")]
+unsafe fn uv_row_neon(matrix: &Matrix, src: &[u8], u: &mut [u8], v: &mut [u8]) {
+  let blocks = u.len() / 8;
+
+  for ((pixels, u), v) in src
+    .chunks_exact(32)
+    .zip(u.chunks_exact_mut(8))
+    .zip(v.chunks_exact_mut(8))
+  {
+    let [u_values, v_values] = chroma_neon(matrix, load_neon(pixels));
+    vst1_u8(u.as_mut_ptr(), vqmovun_s16(u_values));
+    vst1_u8(v.as_mut_ptr(), vqmovun_s16(v_values));
+  }
+
+  super::uv_row(
+    matrix,
+    &src[blocks * 32..],
+    &mut u[blocks * 8..],
+    &mut v[blocks * 8..],
+  );
+}
+
+#[target_feature(enable = "neon")]
+unsafe fn uv_row_sampled_neon(matrix: &Matrix, src: &[u8], u: &mut [u8], v: &mut [u8]) {
+  let blocks = src.len() / 64;
+
+  for ((pixels, u), v) in src
+    .chunks_exact(64)
+    .zip(u.chunks_exact_mut(8))
+    .zip(v.chunks_exact_mut(8))
+  {
+    let [u_values, v_values] = chroma_neon(matrix, load_even_neon(pixels));
+    vst1_u8(u.as_mut_ptr(), vqmovun_s16(u_values));
+    vst1_u8(v.as_mut_ptr(), vqmovun_s16(v_values));
+  }
+
+  super::uv_row_sampled(
+    matrix,
+    &src[blocks * 64..],
+    &mut u[blocks * 8..],
+    &mut v[blocks * 8..],
+  );
+}
+
+#[target_feature(enable = "neon")]
+unsafe fn uv_row_averaged_neon(
+  matrix: &Matrix,
+  top: &[u8],
+  bottom: &[u8],
+  u: &mut [u8],
+  v: &mut [u8],
+) {
+  let blocks = top.len() / 64;
+
+  for (((top, bottom), u), v) in top
+    .chunks_exact(64)
+    .zip(bottom.chunks_exact(64))
+    .zip(u.chunks_exact_mut(8))
+    .zip(v.chunks_exact_mut(8))
+  {
+    let [u_low, v_low] = column_sums_neon(matrix, &top[..32], &bottom[..32]);
+    let [u_high, v_high] = column_sums_neon(matrix, &top[32..], &bottom[32..]);
+
+    // the sums are never negative, so shifting divides them exactly like the
+    // scalar code does
+    let u_values = vshrq_n_s16::<2>(vpaddq_s16(u_low, u_high));
+    let v_values = vshrq_n_s16::<2>(vpaddq_s16(v_low, v_high));
+
+    vst1_u8(u.as_mut_ptr(), vqmovun_s16(u_values));
+    vst1_u8(v.as_mut_ptr(), vqmovun_s16(v_values));
+  }
+
+  super::uv_row_averaged(
+    matrix,
+    &top[blocks * 64..],
+    &bottom[blocks * 64..],
+    &mut u[blocks * 8..],
+    &mut v[blocks * 8..],
+  );
+}
+
+/// Load 8 pixels and split them into the blue, green and red channel
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn load_neon(pixels: &[u8]) -> [uint8x8_t; 3] {
+  let pixels = vld4_u8(pixels[..32].as_ptr());
+  [pixels.0, pixels.1, pixels.2]
+}
+
+/// Load 16 pixels and keep the channels of the 8 with an even index
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn load_even_neon(pixels: &[u8]) -> [uint8x8_t; 3] {
+  let pixels = vld4q_u8(pixels[..64].as_ptr());
+  [
+    vget_low_u8(vuzp1q_u8(pixels.0, pixels.0)),
+    vget_low_u8(vuzp1q_u8(pixels.1, pixels.1)),
+    vget_low_u8(vuzp1q_u8(pixels.2, pixels.2)),
+  ]
+}
+
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn luma_neon(matrix: &Matrix, [b, g, r]: [uint8x8_t; 3]) -> uint8x8_t {
+  let [c_r, c_g, c_b] = matrix.y;
+
+  let sum = vmull_u8(r, vdup_n_u8(c_r as u8));
+  let sum = vmlal_u8(sum, g, vdup_n_u8(c_g as u8));
+  let sum = vmlal_u8(sum, b, vdup_n_u8(c_b as u8));
+  let sum = vaddq_u16(sum, vdupq_n_u16(128));
+
+  // the sum fits into 16 bits only when unsigned
+  vadd_u8(vshrn_n_u16::<8>(sum), vdup_n_u8(matrix.y_offset as u8))
+}
+
+/// Products of the channels with one row of the matrix, added up
+///
+/// The intermediate sums may wrap around, but the results fit into the lanes.
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn dot_neon([c_r, c_g, c_b]: [i32; 3], [b, g, r]: [int16x8_t; 3]) -> int16x8_t {
+  let sum = vmulq_n_s16(r, c_r as i16);
+  let sum = vmlaq_n_s16(sum, g, c_g as i16);
+  vmlaq_n_s16(sum, b, c_b as i16)
+}
+
+/// U and V before clamping
+///
+/// In full range the rounding can overflow the lanes by one. Saturating
+/// there gives the same result as clamping afterwards.
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn chroma_neon(matrix: &Matrix, [b, g, r]: [uint8x8_t; 3]) -> [int16x8_t; 2] {
+  let b = vreinterpretq_s16_u16(vmovl_u8(b));
+  let g = vreinterpretq_s16_u16(vmovl_u8(g));
+  let r = vreinterpretq_s16_u16(vmovl_u8(r));
+
+  let offset = vdupq_n_s16(128);
+
+  let u = vqaddq_s16(dot_neon(matrix.u, [b, g, r]), offset);
+  let v = vqaddq_s16(dot_neon(matrix.v, [b, g, r]), offset);
+
+  [
+    vaddq_s16(div256_neon(u), offset),
+    vaddq_s16(div256_neon(v), offset),
+  ]
+}
+
+/// Division by 256 rounding towards zero, like the integer division
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn div256_neon(x: int16x8_t) -> int16x8_t {
+  let bias = vandq_s16(vshrq_n_s16::<15>(x), vdupq_n_s16(255));
+  vshrq_n_s16::<8>(vaddq_s16(x, bias))
+}
+
+/// U and V of 8 pixels in two rows, added up by columns
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn column_sums_neon(matrix: &Matrix, top: &[u8], bottom: &[u8]) -> [int16x8_t; 2] {
+  let [u_top, v_top] = chroma_neon(matrix, load_neon(top));
+  let [u_bottom, v_bottom] = chroma_neon(matrix, load_neon(bottom));
+
+  [vaddq_s16(u_top, u_bottom), vaddq_s16(v_top, v_bottom)]
+}
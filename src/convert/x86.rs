@@ -0,0 +1,455 @@
+//! SSE2 and AVX2 versions of the row conversions
+//!
+//! The channels are unpacked into 16-bit lanes. All the results fit into 16
+//! bits, so they are the same as with the scalar code.
+
+#![allow(clippy::cast_ptr_alignment)]
+
+#[cfg(target_arch = "x86")]
+#[allow(clippy::wildcard_imports)]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+#[allow(clippy::wildcard_imports)]
+use std::arch::x86_64::*;
+
+use super::{Kernels, Matrix};
+
+pub(super) const SSE2: Kernels = Kernels {
+  y: y_row_sse2,
+  uv: uv_row_sse2,
+  uv_sampled: uv_row_sampled_sse2,
+  uv_averaged: uv_row_averaged_sse2,
+};
+
+pub(super) const AVX2: Kernels = Kernels {
+  y: y_row_avx2,
+  uv: uv_row_avx2,
+  uv_sampled: uv_row_sampled_avx2,
+  uv_averaged: uv_row_averaged_avx2,
+};
+
+#[target_feature(enable = "sse2")]
+unsafe fn y_row_sse2(matrix: &Matrix, src: &[u8], y: &mut [u8]) {
+  let blocks = y.len() / 8;
+
+  for (pixels, y) in src.chunks_exact(32).zip(y.chunks_exact_mut(8)) {
+    store_sse2(y, luma_sse2(matrix, load_sse2(pixels)));
+  }
+
+  super::y_row(matrix, &src[blocks * 32..], &mut y[blocks * 8..]);
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn uv_row_sse2(matrix: &Matrix, src: &[u8], u: &mut [u8], v: &mut [u8]) {
+  let blocks = u.len() / 8;
+
+  for ((pixels, u), v) in src
+    .chunks_exact(32)
+    .zip(u.chunks_exact_mut(8))
+    .zip(v.chunks_exact_mut(8))
+  {
+    let [u_values, v_values] = chroma_sse2(matrix, load_sse2(pixels));
+    store_sse2(u, u_values);
+    store_sse2(v, v_values);
+  }
+
+  super::uv_row(
+    matrix,
+    &src[blocks * 32..],
+    &mut u[blocks * 8..],
+    &mut v[blocks * 8..],
+  );
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn uv_row_sampled_sse2(matrix: &Matrix, src: &[u8], u: &mut [u8], v: &mut [u8]) {
+  let blocks = src.len() / 64;
+
+  for ((pixels, u), v) in src
+    .chunks_exact(64)
+    .zip(u.chunks_exact_mut(8))
+    .zip(v.chunks_exact_mut(8))
+  {
+    let even = channels_sse2(even_sse2(&pixels[..32]), even_sse2(&pixels[32..]));
+    let [u_values, v_values] = chroma_sse2(matrix, even);
+    store_sse2(u, u_values);
+    store_sse2(v, v_values);
+  }
+
+  super::uv_row_sampled(
+    matrix,
+    &src[blocks * 64..],
+    &mut u[blocks * 8..],
+    &mut v[blocks * 8..],
+  );
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn uv_row_averaged_sse2(
+  matrix: &Matrix,
+  top: &[u8],
+  bottom: &[u8],
+  u: &mut [u8],
+  v: &mut [u8],
+) {
+  let blocks = top.len() / 64;
+
+  for (((top, bottom), u), v) in top
+    .chunks_exact(64)
+    .zip(bottom.chunks_exact(64))
+    .zip(u.chunks_exact_mut(8))
+    .zip(v.chunks_exact_mut(8))
+  {
+    let [u_low, v_low] = block_sums_sse2(matrix, &top[..32], &bottom[..32]);
+    let [u_high, v_high] = block_sums_sse2(matrix, &top[32..], &bottom[32..]);
+
+    // the sums are never negative, so shifting divides them exactly like the
+    // scalar code does
+    store_sse2(u, _mm_srai_epi16(_mm_packs_epi32(u_low, u_high), 2));
+    store_sse2(v, _mm_srai_epi16(_mm_packs_epi32(v_low, v_high), 2));
+  }
+
+  super::uv_row_averaged(
+    matrix,
+    &top[blocks * 64..],
+    &bottom[blocks * 64..],
+    &mut u[blocks * 8..],
+    &mut v[blocks * 8..],
+  );
+}
+
+/// Load 8 pixels and split them into channels
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn load_sse2(pixels: &[u8]) -> [__m128i; 3] {
+  let low = _mm_loadu_si128(pixels.as_ptr().cast());
+  let high = _mm_loadu_si128(pixels[16..].as_ptr().cast());
+  channels_sse2(low, high)
+}
+
+/// Load 8 pixels and keep the 4 with an even index
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn even_sse2(pixels: &[u8]) -> __m128i {
+  let low = _mm_shuffle_epi32(_mm_loadu_si128(pixels.as_ptr().cast()), 0b10_00_10_00);
+  let high = _mm_shuffle_epi32(_mm_loadu_si128(pixels[16..].as_ptr().cast()), 0b10_00_10_00);
+  _mm_unpacklo_epi64(low, high)
+}
+
+/// Blue, green and red channel of two vectors of 4 pixels, in 16-bit lanes
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn channels_sse2(low: __m128i, high: __m128i) -> [__m128i; 3] {
+  let mask = _mm_set1_epi32(0xFF);
+
+  let b = _mm_packs_epi32(_mm_and_si128(low, mask), _mm_and_si128(high, mask));
+  let g = _mm_packs_epi32(
+    _mm_and_si128(_mm_srli_epi32(low, 8), mask),
+    _mm_and_si128(_mm_srli_epi32(high, 8), mask),
+  );
+  let r = _mm_packs_epi32(
+    _mm_and_si128(_mm_srli_epi32(low, 16), mask),
+    _mm_and_si128(_mm_srli_epi32(high, 16), mask),
+  );
+
+  [b, g, r]
+}
+
+/// Products of the channels with one row of the matrix, added up
+///
+/// The intermediate sums may wrap around, but the results fit into the lanes.
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn dot_sse2([c_r, c_g, c_b]: [i32; 3], [b, g, r]: [__m128i; 3]) -> __m128i {
+  _mm_add_epi16(
+    _mm_add_epi16(
+      _mm_mullo_epi16(r, _mm_set1_epi16(c_r as i16)),
+      _mm_mullo_epi16(g, _mm_set1_epi16(c_g as i16)),
+    ),
+    _mm_mullo_epi16(b, _mm_set1_epi16(c_b as i16)),
+  )
+}
+
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn luma_sse2(matrix: &Matrix, channels: [__m128i; 3]) -> __m128i {
+  let sum = _mm_add_epi16(dot_sse2(matrix.y, channels), _mm_set1_epi16(128));
+
+  // the sum fits into 16 bits only when unsigned
+  _mm_add_epi16(
+    _mm_srli_epi16(sum, 8),
+    _mm_set1_epi16(matrix.y_offset as i16),
+  )
+}
+
+/// U and V before clamping
+///
+/// In full range the rounding can overflow the lanes by one. Saturating
+/// there gives the same result as clamping afterwards.
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn chroma_sse2(matrix: &Matrix, channels: [__m128i; 3]) -> [__m128i; 2] {
+  let offset = _mm_set1_epi16(128);
+
+  let u = _mm_adds_epi16(dot_sse2(matrix.u, channels), offset);
+  let v = _mm_adds_epi16(dot_sse2(matrix.v, channels), offset);
+
+  [
+    _mm_add_epi16(div256_sse2(u), offset),
+    _mm_add_epi16(div256_sse2(v), offset),
+  ]
+}
+
+/// Division by 256 rounding towards zero, like the integer division
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn div256_sse2(x: __m128i) -> __m128i {
+  let bias = _mm_and_si128(_mm_srai_epi16(x, 15), _mm_set1_epi16(255));
+  _mm_srai_epi16(_mm_add_epi16(x, bias), 8)
+}
+
+/// Sums of U and V over the 2 by 2 blocks of 8 pixels in two rows, in 32-bit
+/// lanes
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn block_sums_sse2(matrix: &Matrix, top: &[u8], bottom: &[u8]) -> [__m128i; 2] {
+  let [u_top, v_top] = chroma_sse2(matrix, load_sse2(top));
+  let [u_bottom, v_bottom] = chroma_sse2(matrix, load_sse2(bottom));
+
+  // adds up the neighbouring lanes
+  let ones = _mm_set1_epi16(1);
+
+  [
+    _mm_madd_epi16(_mm_add_epi16(u_top, u_bottom), ones),
+    _mm_madd_epi16(_mm_add_epi16(v_top, v_bottom), ones),
+  ]
+}
+
+/// Clamp 8 values into bytes and store them
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn store_sse2(dst: &mut [u8], values: __m128i) {
+  _mm_storel_epi64(
+    dst[..8].as_mut_ptr().cast(),
+    _mm_packus_epi16(values, values),
+  );
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn y_row_avx2(matrix: &Matrix, src: &[u8], y: &mut [u8]) {
+  let blocks = y.len() / 16;
+
+  for (pixels, y) in src.chunks_exact(64).zip(y.chunks_exact_mut(16)) {
+    store_avx2(y, luma_avx2(matrix, load_avx2(pixels)));
+  }
+
+  y_row_sse2(matrix, &src[blocks * 64..], &mut y[blocks * 16..]);
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn uv_row_avx2(matrix: &Matrix, src: &[u8], u: &mut [u8], v: &mut [u8]) {
+  let blocks = u.len() / 16;
+
+  for ((pixels, u), v) in src
+    .chunks_exact(64)
+    .zip(u.chunks_exact_mut(16))
+    .zip(v.chunks_exact_mut(16))
+  {
+    let [u_values, v_values] = chroma_avx2(matrix, load_avx2(pixels));
+    store_avx2(u, u_values);
+    store_avx2(v, v_values);
+  }
+
+  uv_row_sse2(
+    matrix,
+    &src[blocks * 64..],
+    &mut u[blocks * 16..],
+    &mut v[blocks * 16..],
+  );
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn uv_row_sampled_avx2(matrix: &Matrix, src: &[u8], u: &mut [u8], v: &mut [u8]) {
+  let blocks = src.len() / 128;
+
+  for ((pixels, u), v) in src
+    .chunks_exact(128)
+    .zip(u.chunks_exact_mut(16))
+    .zip(v.chunks_exact_mut(16))
+  {
+    let even = channels_avx2(even_avx2(&pixels[..64]), even_avx2(&pixels[64..]));
+    let [u_values, v_values] = chroma_avx2(matrix, even);
+    store_avx2(u, u_values);
+    store_avx2(v, v_values);
+  }
+
+  uv_row_sampled_sse2(
+    matrix,
+    &src[blocks * 128..],
+    &mut u[blocks * 16..],
+    &mut v[blocks * 16..],
+  );
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn uv_row_averaged_avx2(
+  matrix: &Matrix,
+  top: &[u8],
+  bottom: &[u8],
+  u: &mut [u8],
+  v: &mut [u8],
+) {
+  let blocks = top.len() / 128;
+
+  for (((top, bottom), u), v) in top
+    .chunks_exact(128)
+    .zip(bottom.chunks_exact(128))
+    .zip(u.chunks_exact_mut(16))
+    .zip(v.chunks_exact_mut(16))
+  {
+    let [u_low, v_low] = block_sums_avx2(matrix, &top[..64], &bottom[..64]);
+    let [u_high, v_high] = block_sums_avx2(matrix, &top[64..], &bottom[64..]);
+
+    let u_sums = order_avx2(_mm256_packs_epi32(u_low, u_high));
+    let v_sums = order_avx2(_mm256_packs_epi32(v_low, v_high));
+
+    store_avx2(u, _mm256_srai_epi16(u_sums, 2));
+    store_avx2(v, _mm256_srai_epi16(v_sums, 2));
+  }
+
+  uv_row_averaged_sse2(
+    matrix,
+    &top[blocks * 128..],
+    &bottom[blocks * 128..],
+    &mut u[blocks * 16..],
+    &mut v[blocks * 16..],
+  );
+}
+
+/// Load 16 pixels and split them into channels
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn load_avx2(pixels: &[u8]) -> [__m256i; 3] {
+  let low = _mm256_loadu_si256(pixels.as_ptr().cast());
+  let high = _mm256_loadu_si256(pixels[32..].as_ptr().cast());
+  channels_avx2(low, high)
+}
+
+/// Load 16 pixels and keep the 8 with an even index
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn even_avx2(pixels: &[u8]) -> __m256i {
+  let low = _mm256_shuffle_epi32(_mm256_loadu_si256(pixels.as_ptr().cast()), 0b10_00_10_00);
+  let high = _mm256_shuffle_epi32(
+    _mm256_loadu_si256(pixels[32..].as_ptr().cast()),
+    0b10_00_10_00,
+  );
+  order_avx2(_mm256_unpacklo_epi64(low, high))
+}
+
+/// Blue, green and red channel of two vectors of 8 pixels, in 16-bit lanes
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn channels_avx2(low: __m256i, high: __m256i) -> [__m256i; 3] {
+  let mask = _mm256_set1_epi32(0xFF);
+
+  let b = _mm256_packs_epi32(_mm256_and_si256(low, mask), _mm256_and_si256(high, mask));
+  let g = _mm256_packs_epi32(
+    _mm256_and_si256(_mm256_srli_epi32(low, 8), mask),
+    _mm256_and_si256(_mm256_srli_epi32(high, 8), mask),
+  );
+  let r = _mm256_packs_epi32(
+    _mm256_and_si256(_mm256_srli_epi32(low, 16), mask),
+    _mm256_and_si256(_mm256_srli_epi32(high, 16), mask),
+  );
+
+  [order_avx2(b), order_avx2(g), order_avx2(r)]
+}
+
+/// Undo the interleaving of the two 128-bit halves caused by packing
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn order_avx2(x: __m256i) -> __m256i {
+  _mm256_permute4x64_epi64(x, 0b11_01_10_00)
+}
+
+/// Products of the channels with one row of the matrix, added up
+///
+/// The intermediate sums may wrap around, but the results fit into the lanes.
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn dot_avx2([c_r, c_g, c_b]: [i32; 3], [b, g, r]: [__m256i; 3]) -> __m256i {
+  _mm256_add_epi16(
+    _mm256_add_epi16(
+      _mm256_mullo_epi16(r, _mm256_set1_epi16(c_r as i16)),
+      _mm256_mullo_epi16(g, _mm256_set1_epi16(c_g as i16)),
+    ),
+    _mm256_mullo_epi16(b, _mm256_set1_epi16(c_b as i16)),
+  )
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn luma_avx2(matrix: &Matrix, channels: [__m256i; 3]) -> __m256i {
+  let sum = _mm256_add_epi16(dot_avx2(matrix.y, channels), _mm256_set1_epi16(128));
+
+  // the sum fits into 16 bits only when unsigned
+  _mm256_add_epi16(
+    _mm256_srli_epi16(sum, 8),
+    _mm256_set1_epi16(matrix.y_offset as i16),
+  )
+}
+
+/// U and V before clamping
+///
+/// In full range the rounding can overflow the lanes by one. Saturating
+/// there gives the same result as clamping afterwards.
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn chroma_avx2(matrix: &Matrix, channels: [__m256i; 3]) -> [__m256i; 2] {
+  let offset = _mm256_set1_epi16(128);
+
+  let u = _mm256_adds_epi16(dot_avx2(matrix.u, channels), offset);
+  let v = _mm256_adds_epi16(dot_avx2(matrix.v, channels), offset);
+
+  [
+    _mm256_add_epi16(div256_avx2(u), offset),
+    _mm256_add_epi16(div256_avx2(v), offset),
+  ]
+}
+
+/// Division by 256 rounding towards zero, like the integer division
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn div256_avx2(x: __m256i) -> __m256i {
+  let bias = _mm256_and_si256(_mm256_srai_epi16(x, 15), _mm256_set1_epi16(255));
+  _mm256_srai_epi16(_mm256_add_epi16(x, bias), 8)
+}
+
+/// Sums of U and V over the 2 by 2 blocks of 16 pixels in two rows, in 32-bit
+/// lanes
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn block_sums_avx2(matrix: &Matrix, top: &[u8], bottom: &[u8]) -> [__m256i; 2] {
+  let [u_top, v_top] = chroma_avx2(matrix, load_avx2(top));
+  let [u_bottom, v_bottom] = chroma_avx2(matrix, load_avx2(bottom));
+
+  // adds up the neighbouring lanes
+  let ones = _mm256_set1_epi16(1);
+
+  [
+    _mm256_madd_epi16(_mm256_add_epi16(u_top, u_bottom), ones),
+    _mm256_madd_epi16(_mm256_add_epi16(v_top, v_bottom), ones),
+  ]
+}
+
+/// Clamp 16 values into bytes and store them
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn store_avx2(dst: &mut [u8], values: __m256i) {
+  let packed = _mm256_permute4x64_epi64(_mm256_packus_epi16(values, values), 0b00_00_10_00);
+  _mm_storeu_si128(
+    dst[..16].as_mut_ptr().cast(),
+    _mm256_castsi256_si128(packed),
+  );
+}
@@ -0,0 +1,636 @@
+//! VP8 and VP9 encoding with libvpx
+//!
+//! libvpx is called directly, so that all its settings can be tuned. The
+//! timestamps are in milliseconds.
+
+use std::{
+  ffi::CStr,
+  fmt,
+  marker::PhantomData,
+  mem::MaybeUninit,
+  ops::RangeInclusive,
+  os::raw::{c_int, c_uint, c_ulong},
+  ptr, slice,
+};
+
+use clap::ValueEnum;
+use vpx_sys::{
+  vp8e_enc_control_id, vpx_codec_control_, vpx_codec_ctx_t, vpx_codec_cx_pkt_kind,
+  vpx_codec_destroy, vpx_codec_enc_cfg_t, vpx_codec_enc_config_default, vpx_codec_enc_init_ver,
+  vpx_codec_encode, vpx_codec_err_t, vpx_codec_err_to_string, vpx_codec_error_detail,
+  vpx_codec_get_cx_data, vpx_codec_iter_t, vpx_codec_vp8_cx, vpx_codec_vp9_cx, vpx_color_range,
+  vpx_color_space, vpx_enc_frame_flags_t, vpx_image_t, vpx_img_wrap, vpx_rc_mode,
+  VPX_DL_BEST_QUALITY, VPX_DL_GOOD_QUALITY, VPX_DL_REALTIME, VPX_EFLAG_FORCE_KF,
+  VPX_ENCODER_ABI_VERSION, VPX_FRAME_IS_KEY,
+};
+
+use crate::yuv::{ColorSpace, PlaneLayout, Range, YuvFrame};
+
+/// Highest quantizer, and the highest CQ level
+const MAX_QUANTIZER: u32 = 63;
+
+/// Keyframe distance in frames given to libvpx when the keyframes are
+/// forced by time, so it doesn't add its own in between
+const UNLIMITED_KEYFRAME_DISTANCE: u32 = 99_999;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum Codec {
+  #[default]
+  VP8,
+  VP9,
+}
+
+impl Codec {
+  /// Valid values of `--cpu-used`
+  fn cpu_used_range(self) -> RangeInclusive<i32> {
+    match self {
+      Self::VP8 => -16..=16,
+      Self::VP9 => -9..=9,
+    }
+  }
+}
+
+impl fmt::Display for Codec {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let string = match self {
+      Self::VP8 => "vp8",
+      Self::VP9 => "vp9",
+    };
+    write!(f, "{string}")
+  }
+}
+
+/// How the bitrate is managed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum RateControl {
+  /// Variable bitrate, averaging the target
+  #[default]
+  Vbr,
+  /// Constant bitrate, best for streaming
+  Cbr,
+  /// Constant quality set by --cq-level, with the bitrate as the upper limit
+  Cq,
+}
+
+impl fmt::Display for RateControl {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let string = match self {
+      Self::Vbr => "vbr",
+      Self::Cbr => "cbr",
+      Self::Cq => "cq",
+    };
+    write!(f, "{string}")
+  }
+}
+
+/// How much time the encoder may spend on a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum Deadline {
+  /// As fast as needed for live recording
+  #[default]
+  Realtime,
+  /// Better quality, may not keep up with the frame rate
+  Good,
+  /// Best quality, far too slow for live recording
+  Best,
+}
+
+impl fmt::Display for Deadline {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let string = match self {
+      Self::Realtime => "realtime",
+      Self::Good => "good",
+      Self::Best => "best",
+    };
+    write!(f, "{string}")
+  }
+}
+
+/// Settings of the encoder beyond the size and the bitrate, libvpx picks the
+/// ones that aren't set
+#[derive(Debug, Clone, Copy)]
+pub struct Tuning {
+  pub rate_control: RateControl,
+  pub cq_level: Option<u32>,
+  /// Speed, higher is faster and worse
+  pub cpu_used: Option<i32>,
+  pub deadline: Deadline,
+  pub threads: u32,
+  /// VP9 only
+  pub row_mt: bool,
+  /// Base 2 logarithm, VP9 only
+  pub tile_columns: Option<u32>,
+  pub lag_in_frames: Option<u32>,
+  pub min_quantizer: Option<u32>,
+  pub max_quantizer: Option<u32>,
+}
+
+impl Tuning {
+  /// Check the settings that depend on each other or on the codec
+  pub fn check(&self, codec: Codec) -> std::result::Result<(), String> {
+    if codec == Codec::VP8 && self.row_mt {
+      return Err("--row-mt works only with VP9".into());
+    }
+    if codec == Codec::VP8 && self.tile_columns.is_some() {
+      return Err("--tile-columns works only with VP9".into());
+    }
+
+    let cpu_used_range = codec.cpu_used_range();
+    if let Some(cpu_used) = self
+      .cpu_used
+      .filter(|speed| !cpu_used_range.contains(speed))
+    {
+      return Err(format!(
+        "--cpu-used {cpu_used} must be between {} and {} with {codec}",
+        cpu_used_range.start(),
+        cpu_used_range.end()
+      ));
+    }
+
+    match (self.rate_control, self.cq_level) {
+      (RateControl::Cq, None) => return Err("--rate-control cq needs --cq-level".into()),
+      (RateControl::Vbr | RateControl::Cbr, Some(_)) => {
+        return Err("--cq-level works only with --rate-control cq".into())
+      }
+      _ => {}
+    }
+
+    let min = self.min_quantizer.unwrap_or(0);
+    let max = self.max_quantizer.unwrap_or(MAX_QUANTIZER);
+    if min > max {
+      return Err(format!("--min-q {min} is above --max-q {max}"));
+    }
+
+    if let Some(level) = self.cq_level.filter(|level| !(min..=max).contains(level)) {
+      return Err(format!(
+        "--cq-level {level} must be between the quantizers {min} and {max}"
+      ));
+    }
+
+    Ok(())
+  }
+}
+
+/// Longest distance between two keyframes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keyint {
+  /// Milliseconds, kept by forcing the keyframes, so it works with a
+  /// variable frame rate
+  Time(i64),
+  /// Frames, kept by libvpx
+  Frames(u32),
+}
+
+impl Keyint {
+  /// Parse seconds as `2s` or `0.5s`, or frames as `120`
+  pub fn parse(s: &str) -> std::result::Result<Self, String> {
+    let keyint = match s.trim().strip_suffix('s') {
+      Some(seconds) => {
+        let seconds: f64 = seconds
+          .trim()
+          .parse()
+          .map_err(|e| format!("Invalid keyframe interval `{s}`: {e}"))?;
+        Self::Time((seconds * 1000.0).round() as i64)
+      }
+      None => Self::Frames(
+        s.trim()
+          .parse()
+          .map_err(|e| format!("Expected frames or seconds like `2s`, got `{s}`: {e}"))?,
+      ),
+    };
+
+    if matches!(keyint, Self::Time(..=0) | Self::Frames(0)) {
+      return Err(format!("The keyframe interval `{s}` must be positive"));
+    }
+
+    Ok(keyint)
+  }
+}
+
+/// Everything the encoder is set up with
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+  pub codec: Codec,
+  pub width: u32,
+  pub height: u32,
+  /// Target bitrate in kbps
+  pub bitrate: u32,
+  /// Signalled in VP9 streams, VP8 can't mark them
+  pub colorspace: ColorSpace,
+  pub range: Range,
+  /// libvpx picks the keyframes without it
+  pub keyint: Option<Keyint>,
+  pub tuning: Tuning,
+}
+
+#[derive(Debug)]
+pub enum Error {
+  /// A call into libvpx failed
+  Vpx { call: &'static str, message: String },
+  /// The codec can't encode frames in this layout
+  UnsupportedLayout(Codec, PlaneLayout),
+  /// The frame doesn't match the configuration of the encoder
+  Mismatch {
+    expected: (PlaneLayout, usize, usize),
+    found: (PlaneLayout, usize, usize),
+  },
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Vpx { call, message } => write!(f, "{call} failed: {message}"),
+      Self::UnsupportedLayout(codec, layout) => {
+        write!(f, "{codec} can't encode {layout} frames")
+      }
+      Self::Mismatch {
+        expected: (expected, expected_width, expected_height),
+        found: (found, found_width, found_height),
+      } => write!(
+        f,
+        "Expected a {expected} frame {expected_width}x{expected_height}, got {found} \
+         {found_width}x{found_height}"
+      ),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Turn the result of a libvpx call into an error, with the details the
+/// encoder gives when there is one
+fn check(ctx: Option<&vpx_codec_ctx_t>, call: &'static str, result: vpx_codec_err_t) -> Result<()> {
+  if result == vpx_codec_err_t::VPX_CODEC_OK {
+    return Ok(());
+  }
+
+  // SAFETY: libvpx returns static strings, and the details live in the context
+  let mut message = unsafe { CStr::from_ptr(vpx_codec_err_to_string(result)) }
+    .to_string_lossy()
+    .into_owned();
+
+  if let Some(ctx) = ctx {
+    let detail = unsafe { vpx_codec_error_detail(ctx) };
+    if !detail.is_null() {
+      let detail = unsafe { CStr::from_ptr(detail) }.to_string_lossy();
+      message = format!("{message} ({detail})");
+    }
+  }
+
+  Err(Error::Vpx { call, message })
+}
+
+/// Encoded frame
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+  pub data: &'a [u8],
+  pub key: bool,
+  pub pts: i64,
+}
+
+/// Encoder accepting only frames in the layout and size it was set up for
+pub struct Encoder {
+  /// Boxed, so it stays in place for libvpx
+  ctx: Box<vpx_codec_ctx_t>,
+  layout: PlaneLayout,
+  width: usize,
+  height: usize,
+  deadline: c_ulong,
+  /// Timestamp of the previous frame
+  last_pts: Option<i64>,
+  /// Longest time between two forced keyframes in milliseconds
+  keyint: Option<i64>,
+  /// Timestamp of the last forced keyframe, or the first frame
+  last_key: Option<i64>,
+  /// Whether to make the next frame a keyframe
+  force_key: bool,
+}
+
+impl Encoder {
+  pub fn new(config: &Config, layout: PlaneLayout) -> Result<Self> {
+    // VP8 supports only 4:2:0
+    if config.codec == Codec::VP8 && layout != PlaneLayout::I420 {
+      return Err(Error::UnsupportedLayout(config.codec, layout));
+    }
+
+    let tuning = &config.tuning;
+    let iface = unsafe {
+      match config.codec {
+        Codec::VP8 => vpx_codec_vp8_cx(),
+        Codec::VP9 => vpx_codec_vp9_cx(),
+      }
+    };
+
+    // SAFETY: the configuration is plain data, filled in by libvpx
+    let mut cfg: vpx_codec_enc_cfg_t = unsafe { MaybeUninit::zeroed().assume_init() };
+    check(None, "vpx_codec_enc_config_default", unsafe {
+      vpx_codec_enc_config_default(iface, ptr::addr_of_mut!(cfg), 0)
+    })?;
+
+    cfg.g_w = config.width;
+    cfg.g_h = config.height;
+    cfg.g_timebase.num = 1;
+    cfg.g_timebase.den = 1000;
+    cfg.g_threads = tuning.threads;
+    cfg.rc_target_bitrate = config.bitrate;
+    cfg.rc_end_usage = match tuning.rate_control {
+      RateControl::Vbr => vpx_rc_mode::VPX_VBR,
+      RateControl::Cbr => vpx_rc_mode::VPX_CBR,
+      RateControl::Cq => vpx_rc_mode::VPX_CQ,
+    };
+
+    if let Some(lag) = tuning.lag_in_frames {
+      cfg.g_lag_in_frames = lag;
+    }
+    if let Some(min) = tuning.min_quantizer {
+      cfg.rc_min_quantizer = min;
+    }
+    if let Some(max) = tuning.max_quantizer {
+      cfg.rc_max_quantizer = max;
+    }
+
+    match config.keyint {
+      Some(Keyint::Frames(frames)) => cfg.kf_max_dist = frames,
+      Some(Keyint::Time(_)) => cfg.kf_max_dist = UNLIMITED_KEYFRAME_DISTANCE,
+      None => {}
+    }
+
+    // 4:4:4 needs profile 1
+    if layout == PlaneLayout::I444 {
+      cfg.g_profile = 1;
+    }
+
+    // SAFETY: zeroed is the state libvpx expects before the initialization
+    let mut ctx: Box<vpx_codec_ctx_t> = Box::new(unsafe { MaybeUninit::zeroed().assume_init() });
+    check(None, "vpx_codec_enc_init_ver", unsafe {
+      vpx_codec_enc_init_ver(
+        ptr::addr_of_mut!(*ctx),
+        iface,
+        ptr::addr_of!(cfg),
+        0,
+        VPX_ENCODER_ABI_VERSION as c_int,
+      )
+    })?;
+
+    let mut encoder = Self {
+      ctx,
+      layout,
+      width: config.width as usize,
+      height: config.height as usize,
+      deadline: match tuning.deadline {
+        Deadline::Realtime => VPX_DL_REALTIME,
+        Deadline::Good => VPX_DL_GOOD_QUALITY,
+        Deadline::Best => VPX_DL_BEST_QUALITY,
+      } as c_ulong,
+      last_pts: None,
+      keyint: match config.keyint {
+        Some(Keyint::Time(millis)) => Some(millis),
+        _ => None,
+      },
+      last_key: None,
+      force_key: false,
+    };
+
+    encoder.set_controls(config)?;
+
+    Ok(encoder)
+  }
+
+  fn set_controls(&mut self, config: &Config) -> Result<()> {
+    let tuning = &config.tuning;
+
+    if let Some(cpu_used) = tuning.cpu_used {
+      self.control(vp8e_enc_control_id::VP8E_SET_CPUUSED, cpu_used)?;
+    }
+    if let Some(level) = tuning.cq_level {
+      self.control(vp8e_enc_control_id::VP8E_SET_CQ_LEVEL, level as c_int)?;
+    }
+
+    if config.codec == Codec::VP9 {
+      if tuning.row_mt {
+        self.control(vp8e_enc_control_id::VP9E_SET_ROW_MT, 1)?;
+      }
+      if let Some(columns) = tuning.tile_columns {
+        self.control(vp8e_enc_control_id::VP9E_SET_TILE_COLUMNS, columns as c_int)?;
+      }
+
+      let colorspace = match config.colorspace {
+        ColorSpace::Bt601 => vpx_color_space::VPX_CS_BT_601,
+        ColorSpace::Bt709 => vpx_color_space::VPX_CS_BT_709,
+      };
+      let range = match config.range {
+        Range::Limited => vpx_color_range::VPX_CR_STUDIO_RANGE,
+        Range::Full => vpx_color_range::VPX_CR_FULL_RANGE,
+      };
+      self.control(
+        vp8e_enc_control_id::VP9E_SET_COLOR_SPACE,
+        colorspace as c_int,
+      )?;
+      self.control(vp8e_enc_control_id::VP9E_SET_COLOR_RANGE, range as c_int)?;
+    }
+
+    Ok(())
+  }
+
+  fn ctx(&mut self) -> *mut vpx_codec_ctx_t {
+    ptr::addr_of_mut!(*self.ctx)
+  }
+
+  fn control(&mut self, id: vp8e_enc_control_id, value: c_int) -> Result<()> {
+    let result = unsafe { vpx_codec_control_(self.ctx(), id as c_int, value) };
+    check(Some(&*self.ctx), "vpx_codec_control", result)
+  }
+
+  pub fn encode(&mut self, pts: i64, frame: &YuvFrame) -> Result<Packets<'_>> {
+    let found = (frame.layout(), frame.width(), frame.height());
+    let expected = (self.layout, self.width, self.height);
+
+    if found != expected {
+      return Err(Error::Mismatch { expected, found });
+    }
+
+    let mut storage = MaybeUninit::<vpx_image_t>::zeroed();
+    // SAFETY: the frame has the size and the layout of the image, and libvpx
+    // only reads the data
+    let wrapped = unsafe {
+      vpx_img_wrap(
+        storage.as_mut_ptr(),
+        self.layout.vpx_format(),
+        self.width as c_uint,
+        self.height as c_uint,
+        1,
+        frame.data().as_ptr().cast_mut(),
+      )
+    };
+    if wrapped.is_null() {
+      return Err(Error::Vpx {
+        call: "vpx_img_wrap",
+        message: "Can't wrap the frame".into(),
+      });
+    }
+
+    // libvpx lays the planes out for the size rounded up to even, which
+    // doesn't match the packed planes of odd sized frames, so they are
+    // pointed at the planes of the frame
+    let image = unsafe { &mut *wrapped };
+    let [y, u, v] = frame.planes();
+    let [y_stride, u_stride, v_stride] = frame.strides();
+    let (v, v_stride) = match self.layout {
+      // the second sample of every pair of the interleaved plane
+      PlaneLayout::NV12 => (&u[1..], u_stride),
+      PlaneLayout::I420 | PlaneLayout::I444 => (v, v_stride),
+    };
+    for (index, (plane, stride)) in [(y, y_stride), (u, u_stride), (v, v_stride)]
+      .into_iter()
+      .enumerate()
+    {
+      image.planes[index] = plane.as_ptr().cast_mut();
+      image.stride[index] = stride as c_int;
+    }
+
+    // The duration of a frame isn't known until the next one comes, so the
+    // time since the previous one is taken instead. The rate control counts
+    // on it.
+    let duration = self.last_pts.map_or(1, |last| (pts - last).max(1));
+    self.last_pts = Some(pts);
+
+    // the first frame is always a keyframe
+    let due = match (self.last_key, self.keyint) {
+      (None, _) => {
+        self.last_key = Some(pts);
+        false
+      }
+      (Some(last), Some(keyint)) => pts - last >= keyint,
+      (Some(_), None) => false,
+    };
+
+    let mut flags = 0;
+    if due || self.force_key {
+      flags |= VPX_EFLAG_FORCE_KF as vpx_enc_frame_flags_t;
+      self.last_key = Some(pts);
+      self.force_key = false;
+    }
+
+    let result = unsafe {
+      vpx_codec_encode(
+        self.ctx(),
+        image,
+        pts,
+        duration as c_ulong,
+        flags,
+        self.deadline,
+      )
+    };
+    check(Some(&*self.ctx), "vpx_codec_encode", result)?;
+
+    Ok(Packets {
+      ctx: self.ctx(),
+      iter: ptr::null(),
+      encoder: PhantomData,
+    })
+  }
+
+  /// Make the next frame a keyframe, e.g. to start a new part of the replay
+  /// buffer
+  pub fn force_keyframe(&mut self) {
+    self.force_key = true;
+  }
+
+  /// Flush the frames the encoder still holds
+  pub fn finish(self) -> Finish {
+    Finish {
+      encoder: self,
+      iter: ptr::null(),
+      flushed_frames: true,
+    }
+  }
+}
+
+impl Drop for Encoder {
+  fn drop(&mut self) {
+    unsafe {
+      vpx_codec_destroy(self.ctx());
+    }
+  }
+}
+
+/// Frames that are done encoding
+pub struct Packets<'a> {
+  ctx: *mut vpx_codec_ctx_t,
+  iter: vpx_codec_iter_t,
+  /// The frames live until the encoder is called again
+  encoder: PhantomData<&'a mut Encoder>,
+}
+
+impl<'a> Iterator for Packets<'a> {
+  type Item = Frame<'a>;
+
+  fn next(&mut self) -> Option<Frame<'a>> {
+    // SAFETY: the encoder is borrowed for as long as the frames
+    unsafe { next_frame(self.ctx, &mut self.iter) }
+  }
+}
+
+/// The next frame of the encoder, skipping the other packets
+///
+/// # Safety
+///
+/// The frame is valid only until the next call to the encoder.
+unsafe fn next_frame<'a>(
+  ctx: *mut vpx_codec_ctx_t,
+  iter: &mut vpx_codec_iter_t,
+) -> Option<Frame<'a>> {
+  loop {
+    let packet = vpx_codec_get_cx_data(ctx, iter);
+    if packet.is_null() {
+      return None;
+    }
+
+    if (*packet).kind == vpx_codec_cx_pkt_kind::VPX_CODEC_CX_FRAME_PKT {
+      let frame = &(*packet).data.frame;
+      return Some(Frame {
+        data: slice::from_raw_parts(frame.buf.cast(), frame.sz),
+        key: frame.flags & VPX_FRAME_IS_KEY != 0,
+        pts: frame.pts,
+      });
+    }
+  }
+}
+
+/// Encoder flushing the frames it still holds
+///
+/// libvpx may need several flushes to give out every frame it held back, e.g.
+/// with `--lag-in-frames`, so it's flushed until a flush gives no frame.
+pub struct Finish {
+  encoder: Encoder,
+  iter: vpx_codec_iter_t,
+  /// Whether the last flush gave any frame, `true` before the first one
+  flushed_frames: bool,
+}
+
+impl Finish {
+  pub fn next(&mut self) -> Result<Option<Frame<'_>>> {
+    loop {
+      let ctx = self.encoder.ctx();
+
+      // SAFETY: the frame borrows the encoder until the next call
+      if let Some(frame) = unsafe { next_frame(ctx, &mut self.iter) } {
+        self.flushed_frames = true;
+        return Ok(Some(frame));
+      }
+
+      if !self.flushed_frames {
+        return Ok(None);
+      }
+
+      // passing no image asks for the rest of the frames
+      let result = unsafe { vpx_codec_encode(ctx, ptr::null(), -1, 1, 0, self.encoder.deadline) };
+      check(Some(&*self.encoder.ctx), "vpx_codec_encode", result)?;
+
+      self.iter = ptr::null();
+      self.flushed_frames = false;
+    }
+  }
+}
@@ -0,0 +1,74 @@
+//! Failures that end the program, each with its own exit code
+
+use std::{fmt, io};
+
+use rayon::ThreadPoolBuildError;
+
+use crate::encoder;
+
+#[derive(Debug)]
+pub enum Error {
+  /// The daemon can't be reached, or it refused the command
+  Control(String),
+  /// Invalid options or config file
+  Config(String),
+  Capture(String),
+  Audio(io::Error),
+  /// The conversion threads can't be started
+  Convert(ThreadPoolBuildError),
+  Encode(encoder::Error),
+  Mux(String),
+  /// What was being done, and why it failed
+  Io(String, io::Error),
+  /// A thread has panicked, names the part of the recorder it ran
+  Crash(&'static str),
+}
+
+impl Error {
+  /// The codes are listed in the README
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      Self::Control(_) => 1,
+      Self::Config(_) => 2,
+      Self::Capture(_) => 3,
+      Self::Audio(_) => 4,
+      Self::Convert(_) => 5,
+      Self::Encode(_) => 6,
+      Self::Mux(_) => 7,
+      Self::Io(..) => 8,
+      Self::Crash(_) => 9,
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Control(message) | Self::Config(message) | Self::Capture(message) => {
+        write!(f, "{message}")
+      }
+      Self::Audio(e) => write!(f, "Can't record audio: {e}"),
+      Self::Convert(e) => write!(f, "Can't start the conversion threads: {e}"),
+      Self::Encode(e) => write!(f, "Can't encode the video: {e}"),
+      Self::Mux(message) => write!(f, "{message}"),
+      Self::Io(context, e) => write!(f, "{context}: {e}"),
+      Self::Crash(part) => write!(f, "{part} has crashed"),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl From<encoder::Error> for Error {
+  fn from(e: encoder::Error) -> Self {
+    Self::Encode(e)
+  }
+}
+
+impl From<ThreadPoolBuildError> for Error {
+  fn from(e: ThreadPoolBuildError) -> Self {
+    Self::Convert(e)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
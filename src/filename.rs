@@ -0,0 +1,192 @@
+//! Names of the recorded files
+//!
+//! A template is a file name with strftime specifiers for the time the file
+//! is created (`%Y-%m-%d_%H-%M-%S`), and these placeholders:
+//!
+//! - `{n}` is a counter, the first number that gives a new file. `{n:3}` pads
+//!   it with zeros to 3 digits.
+//! - `{display}` is the index of the recorded display, `test` for the test
+//!   pattern
+//! - `{codec}` is `vp8` or `vp9`
+//! - `{width}` and `{height}` are the size of the video
+
+use std::{
+  fmt::Write,
+  fs::{self, File, OpenOptions},
+  io,
+  path::PathBuf,
+};
+
+use chrono::{
+  format::{Item, StrftimeItems},
+  DateTime, Local,
+};
+
+/// Sortable, and unique unless two files are created in the same second
+pub const DEFAULT_TEMPLATE: &str = "%Y-%m-%d_%H-%M-%S.webm";
+
+pub const DEFAULT_SCREENSHOT_TEMPLATE: &str = "%Y-%m-%d_%H-%M-%S.png";
+
+#[derive(Debug, Clone)]
+enum Part {
+  /// Text with strftime specifiers
+  Time(String),
+  /// Counter padded to the width
+  Counter(usize),
+  Display,
+  Codec,
+  Width,
+  Height,
+}
+
+/// Parsed file name template
+#[derive(Debug, Clone)]
+pub struct Template {
+  parts: Vec<Part>,
+}
+
+impl Template {
+  pub fn parse(template: &str) -> Result<Self, String> {
+    if template.is_empty() {
+      return Err("The template is empty".into());
+    }
+
+    let mut parts = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+      let Some(length) = rest[start..].find('}') else {
+        return Err("Unclosed `{` in the template".into());
+      };
+
+      push_time(&mut parts, &rest[..start])?;
+
+      let part = match &rest[start + 1..start + length] {
+        "n" => Part::Counter(0),
+        "display" => Part::Display,
+        "codec" => Part::Codec,
+        "width" => Part::Width,
+        "height" => Part::Height,
+        name => {
+          let width = name.strip_prefix("n:").and_then(|width| width.parse().ok());
+          Part::Counter(width.ok_or_else(|| format!("Unknown placeholder `{{{name}}}`"))?)
+        }
+      };
+
+      parts.push(part);
+      rest = &rest[start + length + 1..];
+    }
+
+    push_time(&mut parts, rest)?;
+
+    Ok(Self { parts })
+  }
+
+  fn has_counter(&self) -> bool {
+    self
+      .parts
+      .iter()
+      .any(|part| matches!(part, Part::Counter(_)))
+  }
+
+  fn render(&self, fields: &Fields, time: &DateTime<Local>, n: u64) -> String {
+    let mut name = String::new();
+
+    for part in &self.parts {
+      // writing into a string can't fail
+      let _ = match part {
+        Part::Time(format) => write!(name, "{}", time.format(format)),
+        Part::Counter(width) => write!(name, "{n:0width$}"),
+        Part::Display => match fields.display {
+          Some(index) => write!(name, "{index}"),
+          None => write!(name, "test"),
+        },
+        Part::Codec => write!(name, "{}", fields.codec),
+        Part::Width => write!(name, "{}", fields.width),
+        Part::Height => write!(name, "{}", fields.height),
+      };
+    }
+
+    name
+  }
+}
+
+/// Check the strftime specifiers, formatting invalid ones would panic
+fn push_time(parts: &mut Vec<Part>, text: &str) -> Result<(), String> {
+  if StrftimeItems::new(text).any(|item| matches!(item, Item::Error)) {
+    return Err(format!("Invalid time format in `{text}`"));
+  }
+
+  if !text.is_empty() {
+    parts.push(Part::Time(text.to_owned()));
+  }
+
+  Ok(())
+}
+
+/// Values of the placeholders
+#[derive(Debug, Clone)]
+pub struct Fields {
+  /// `None` when not recording a display
+  pub display: Option<usize>,
+  pub codec: String,
+  pub width: u32,
+  pub height: u32,
+}
+
+/// Creates the files of a recording
+#[derive(Debug, Clone)]
+pub struct Namer {
+  folder: PathBuf,
+  template: Template,
+  fields: Fields,
+}
+
+impl Namer {
+  pub fn new(folder: PathBuf, template: Template, fields: Fields) -> Self {
+    Self {
+      folder,
+      template,
+      fields,
+    }
+  }
+
+  /// Create a new file in the folder, never overwriting an existing one
+  ///
+  /// With `{n}` the counter goes up until the name is free, otherwise a
+  /// number is appended to the name when it's taken.
+  pub fn create(&self) -> io::Result<(PathBuf, File)> {
+    let time = Local::now();
+    let mut n = 1;
+
+    loop {
+      let name = self.template.render(&self.fields, &time, n);
+      let name = if n == 1 || self.template.has_counter() {
+        name
+      } else {
+        with_suffix(&name, n)
+      };
+
+      let path = self.folder.join(name);
+      if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+      }
+
+      match OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(file) => return Ok((path, file)),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+        Err(e) => return Err(e),
+      }
+    }
+  }
+}
+
+/// `clip.webm` → `clip-2.webm`
+fn with_suffix(name: &str, n: u64) -> String {
+  match name.rsplit_once('.') {
+    Some((stem, extension)) if !stem.is_empty() && !extension.contains('/') => {
+      format!("{stem}-{n}.{extension}")
+    }
+    _ => format!("{name}-{n}"),
+  }
+}
@@ -0,0 +1,488 @@
+//! Completing the track headers written by the muxer
+//!
+//! The muxer can't write every element of the track headers: of the colours it
+//! only marks the range, and it can't set the delays of the audio track.
+//! [`HeaderWriter`] holds back the start of
+//! the file until the Tracks element is complete and adds the missing elements
+//! into it.
+//!
+//! The muxer checks the sizes of the elements against the position in the
+//! file, and later seeks back into the header, so the Tracks element has to
+//! keep its size. The video track is set up with [`ROOM`] as its CodecPrivate,
+//! which is replaced by the new elements and a Void element filling the rest.
+//! If the header can't be completed, the placeholder is turned into a Void
+//! element instead, so the decoder isn't given its zeros.
+
+use std::{
+  io::{self, Seek, SeekFrom, Write},
+  ops::Range,
+};
+
+use crate::{error, yuv::ColorSpace};
+
+const EBML: u32 = 0x1A45_DFA3;
+const SEGMENT: u32 = 0x1853_8067;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const VIDEO: u32 = 0xE0;
+const COLOUR: u32 = 0x55B0;
+const MATRIX_COEFFICIENTS: u32 = 0x55B1;
+const TRANSFER_CHARACTERISTICS: u32 = 0x55BA;
+const PRIMARIES: u32 = 0x55BB;
+const AUDIO: u32 = 0xE1;
+const CODEC_DELAY: u32 = 0x56AA;
+const SEEK_PRE_ROLL: u32 = 0x56BB;
+const VOID: u32 = 0xEC;
+
+/// Placeholder CodecPrivate of the video track, making room for the added
+/// elements
+pub const ROOM: [u8; 64] = [0; 64];
+
+/// Give up on a header that grows past this without a Tracks element
+const MAX_HEADER: usize = 1 << 20;
+
+/// Elements added into the track headers
+#[derive(Debug, Clone, Copy)]
+pub struct Extras {
+  /// Colour matrix of the video, nothing is marked without it
+  pub colorspace: Option<ColorSpace>,
+  /// CodecDelay and SeekPreRoll of the audio track in nanoseconds
+  pub audio_delays: Option<(u64, u64)>,
+}
+
+impl Extras {
+  /// Colour elements of the video, with the code points of ITU-T H.273
+  fn colour(self) -> Vec<(u32, u64)> {
+    let Some(colorspace) = self.colorspace else { return Vec::new(); };
+
+    let matrix = match colorspace {
+      ColorSpace::Bt601 => 6,
+      ColorSpace::Bt709 => 1,
+    };
+
+    // the screens are sRGB, which has the primaries of BT.709 and a transfer
+    // close to its curve, whatever the matrix is
+    vec![
+      (MATRIX_COEFFICIENTS, matrix),
+      (TRANSFER_CHARACTERISTICS, 1),
+      (PRIMARIES, 1),
+    ]
+  }
+}
+
+/// Writer for the muxer, completing the track headers on the way
+///
+/// The file is written from the start of `inner`.
+pub struct HeaderWriter<W> {
+  inner: W,
+  extras: Extras,
+  /// Start of the file until the Tracks element is complete, `None` once it
+  /// was written out
+  header: Option<Vec<u8>>,
+}
+
+impl<W: Write> HeaderWriter<W> {
+  pub fn new(inner: W, extras: Extras) -> Self {
+    Self {
+      inner,
+      extras,
+      header: Some(Vec::new()),
+    }
+  }
+
+  /// Write out the held back header, completed if `tracks` is found
+  fn write_header(&mut self, tracks: Option<Range<usize>>) -> io::Result<()> {
+    let Some(mut header) = self.header.take() else { return Ok(()); };
+
+    let completed =
+      tracks.and_then(|tracks| Some((complete(&header[tracks.clone()], self.extras)?, tracks)));
+    if let Some((content, tracks)) = completed {
+      header[tracks].copy_from_slice(&content);
+    } else {
+      error("Can't complete the track headers of the file");
+      void_placeholder(&mut header);
+    }
+
+    self.inner.write_all(&header)
+  }
+}
+
+impl<W: Write> Write for HeaderWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let Some(header) = &mut self.header else { return self.inner.write(buf); };
+
+    header.extend_from_slice(buf);
+    if let Some(tracks) = find_tracks(header) {
+      self.write_header(Some(tracks))?;
+    } else if header.len() > MAX_HEADER {
+      self.write_header(None)?;
+    }
+
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+impl<W: Write + Seek> Seek for HeaderWriter<W> {
+  fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+    match (&self.header, position) {
+      (None, _) => self.inner.seek(position),
+      // the muxer asks for the position of every element
+      (Some(header), SeekFrom::Current(0)) => Ok(header.len() as u64),
+      (Some(header), SeekFrom::Start(start)) if start == header.len() as u64 => Ok(start),
+      // not expected before the tracks, the header has to be written as it is
+      (Some(_), _) => {
+        self.write_header(None)?;
+        self.inner.seek(position)
+      }
+    }
+  }
+}
+
+/// Position of the content of the Tracks element, once it's complete
+fn find_tracks(header: &[u8]) -> Option<Range<usize>> {
+  let ebml = element(header, 0).filter(|ebml| ebml.id == EBML)?;
+  // the size of the segment is unknown until the file is finished
+  let segment = element(header, ebml.data.end).filter(|segment| segment.id == SEGMENT)?;
+
+  let mut position = segment.data.start;
+  loop {
+    let child = element(header, position)?;
+    if child.data.end > header.len() {
+      return None;
+    }
+    if child.id == TRACKS {
+      return Some(child.data);
+    }
+    position = child.data.end;
+  }
+}
+
+/// New content of the Tracks element with the extras added, of the same
+/// length as `tracks`
+fn complete(tracks: &[u8], extras: Extras) -> Option<Vec<u8>> {
+  let mut out = Vec::with_capacity(tracks.len());
+
+  for (id, data) in children(tracks)? {
+    if id != TRACK_ENTRY {
+      put(&mut out, id, data);
+      continue;
+    }
+
+    let entry = children(data)?;
+    let has = |kind| entry.iter().any(|&(id, _)| id == kind);
+
+    match extras.audio_delays {
+      _ if has(VIDEO) => put(&mut out, id, &video_entry(data, extras)?),
+      Some((codec_delay, seek_pre_roll)) if has(AUDIO) => {
+        let mut audio = data.to_vec();
+        put_uint(&mut audio, CODEC_DELAY, codec_delay);
+        put_uint(&mut audio, SEEK_PRE_ROLL, seek_pre_roll);
+        put(&mut out, id, &audio);
+      }
+      _ => put(&mut out, id, data),
+    }
+  }
+
+  let room = tracks.len().checked_sub(out.len())?;
+  put_void(&mut out, room)?;
+
+  Some(out)
+}
+
+/// The video TrackEntry without the placeholder and with the colours
+fn video_entry(entry: &[u8], extras: Extras) -> Option<Vec<u8>> {
+  let mut out = Vec::new();
+
+  for (id, data) in children(entry)? {
+    match id {
+      CODEC_PRIVATE => {}
+      VIDEO => {
+        let mut video = Vec::new();
+        let mut colour = Vec::new();
+        for (id, data) in children(data)? {
+          if id == COLOUR {
+            colour.extend_from_slice(data);
+          } else {
+            put(&mut video, id, data);
+          }
+        }
+
+        for (id, value) in extras.colour() {
+          put_uint(&mut colour, id, value);
+        }
+        if !colour.is_empty() {
+          put(&mut video, COLOUR, &colour);
+        }
+
+        put(&mut out, VIDEO, &video);
+      }
+      _ => put(&mut out, id, data),
+    }
+  }
+
+  Some(out)
+}
+
+/// Turn the placeholder CodecPrivate into a Void element of the same size,
+/// found by its id and zeros as the elements around it may be incomplete
+fn void_placeholder(header: &mut [u8]) -> Option<()> {
+  let placeholder = (0..header.len()).find_map(|position| {
+    let element = element(header, position).filter(|element| element.id == CODEC_PRIVATE)?;
+    (header.get(element.data.clone())? == ROOM).then_some(position..element.data.end)
+  })?;
+
+  let mut void = Vec::with_capacity(placeholder.len());
+  put_void(&mut void, placeholder.len())?;
+  header[placeholder].copy_from_slice(&void);
+
+  Some(())
+}
+
+/// Header of an element, whose data may not be all there yet
+struct Element {
+  id: u32,
+  data: Range<usize>,
+}
+
+fn element(buffer: &[u8], position: usize) -> Option<Element> {
+  let (id, id_length) = vint(buffer, position, 4)?;
+  let (size, size_length) = vint(buffer, position + id_length, 8)?;
+
+  // the marker bit is a part of the id, but not of the size
+  let size = size & !(1 << (7 * size_length));
+  let start = position + id_length + size_length;
+
+  Some(Element {
+    id: id as u32,
+    data: start..start.saturating_add(size as usize),
+  })
+}
+
+/// Variable length integer with its marker bit, and its length
+fn vint(buffer: &[u8], position: usize, max_length: usize) -> Option<(u64, usize)> {
+  let first = *buffer.get(position)?;
+  let length = first.leading_zeros() as usize + 1;
+  if length > max_length {
+    return None;
+  }
+
+  let bytes = buffer.get(position..position + length)?;
+  let value = bytes
+    .iter()
+    .fold(0, |value, &byte| value << 8 | u64::from(byte));
+
+  Some((value, length))
+}
+
+/// Ids and data of the elements in a complete master element
+fn children(data: &[u8]) -> Option<Vec<(u32, &[u8])>> {
+  let mut children = Vec::new();
+  let mut position = 0;
+
+  while position < data.len() {
+    let child = element(data, position)?;
+    children.push((child.id, data.get(child.data.clone())?));
+    position = child.data.end;
+  }
+
+  Some(children)
+}
+
+fn put(out: &mut Vec<u8>, id: u32, data: &[u8]) {
+  put_id(out, id);
+  let length = (1..=8)
+    .find(|&length| data.len() < (1 << (7 * length)) - 1)
+    .unwrap_or(8);
+  put_size(out, data.len(), length);
+  out.extend_from_slice(data);
+}
+
+fn put_uint(out: &mut Vec<u8>, id: u32, value: u64) {
+  let bytes = value.to_be_bytes();
+  let skip = (value.leading_zeros() / 8).min(7) as usize;
+  put(out, id, &bytes[skip..]);
+}
+
+fn put_id(out: &mut Vec<u8>, id: u32) {
+  let bytes = id.to_be_bytes();
+  let skip = (id.leading_zeros() / 8) as usize;
+  out.extend_from_slice(&bytes[skip..]);
+}
+
+fn put_size(out: &mut Vec<u8>, size: usize, length: usize) {
+  let bytes = (size as u64 | 1 << (7 * length)).to_be_bytes();
+  out.extend_from_slice(&bytes[8 - length..]);
+}
+
+/// Void element taking up exactly `length` bytes
+fn put_void(out: &mut Vec<u8>, length: usize) -> Option<()> {
+  let size_length = if length.checked_sub(2)? < 127 { 1 } else { 8 };
+  let size = length - 1 - size_length;
+
+  put_id(out, VOID);
+  put_size(out, size, size_length);
+  out.resize(out.len() + size, 0);
+
+  Some(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use super::*;
+
+  const TRACK_NUMBER: u32 = 0xD7;
+  const CODEC_ID: u32 = 0x86;
+  const PIXEL_WIDTH: u32 = 0xB0;
+  const RANGE: u32 = 0x55B9;
+  const CHANNELS: u32 = 0x9F;
+  const CLUSTER: u32 = 0x1F43_B675;
+
+  /// Start of a file like the muxer writes it, with the position of the
+  /// Tracks element
+  fn file() -> (Vec<u8>, Range<usize>) {
+    let mut colour = Vec::new();
+    put_uint(&mut colour, RANGE, 1);
+    let mut video = Vec::new();
+    put_uint(&mut video, PIXEL_WIDTH, 1920);
+    put(&mut video, COLOUR, &colour);
+
+    let mut entry = Vec::new();
+    put_uint(&mut entry, TRACK_NUMBER, 1);
+    put(&mut entry, CODEC_ID, b"V_VP9");
+    put(&mut entry, VIDEO, &video);
+    put(&mut entry, CODEC_PRIVATE, &ROOM);
+
+    let mut audio = Vec::new();
+    put_uint(&mut audio, CHANNELS, 2);
+    let mut audio_entry = Vec::new();
+    put_uint(&mut audio_entry, TRACK_NUMBER, 2);
+    put(&mut audio_entry, AUDIO, &audio);
+
+    let mut tracks = Vec::new();
+    put(&mut tracks, TRACK_ENTRY, &entry);
+    put(&mut tracks, TRACK_ENTRY, &audio_entry);
+
+    let mut file = Vec::new();
+    put(&mut file, EBML, b"webm");
+    put_id(&mut file, SEGMENT);
+    put_size(&mut file, (1 << 56) - 1, 8);
+    put_void(&mut file, 40);
+    put(&mut file, TRACKS, &tracks);
+    let end = file.len();
+    put(&mut file, CLUSTER, &[1, 2, 3]);
+
+    (file.clone(), end - tracks.len()..end)
+  }
+
+  /// Children of the element with the id `path` at each level
+  fn find<'a>(data: &'a [u8], path: &[u32]) -> Vec<(u32, &'a [u8])> {
+    path.iter().fold(children(data).unwrap(), |elements, &id| {
+      let (_, data) = elements
+        .into_iter()
+        .find(|&(child, _)| child == id)
+        .unwrap();
+      children(data).unwrap()
+    })
+  }
+
+  #[test]
+  fn elements_are_added_in_place_of_the_placeholder() {
+    let (file, tracks) = file();
+    let extras = Extras {
+      colorspace: Some(ColorSpace::Bt709),
+      audio_delays: Some((6_500_000, 80_000_000)),
+    };
+    let mut writer = HeaderWriter::new(Cursor::new(Vec::new()), extras);
+
+    // the muxer writes the ids byte by byte and checks the positions
+    for (position, byte) in file.iter().enumerate() {
+      assert_eq!(writer.stream_position().unwrap(), position as u64);
+      writer.write_all(&[*byte]).unwrap();
+    }
+    let written = writer.inner.into_inner();
+
+    assert_eq!(written.len(), file.len());
+    assert_eq!(written[..tracks.start], file[..tracks.start]);
+    assert_eq!(written[tracks.end..], file[tracks.end..]);
+
+    let tracks = &written[tracks];
+    let entry = find(tracks, &[TRACK_ENTRY]);
+    assert!(entry.iter().all(|&(id, _)| id != CODEC_PRIVATE));
+
+    let mut colour = find(tracks, &[TRACK_ENTRY, VIDEO, COLOUR]);
+    colour.sort_unstable();
+    assert_eq!(
+      colour,
+      [
+        (MATRIX_COEFFICIENTS, &[1][..]),
+        (RANGE, &[1]),
+        (TRANSFER_CHARACTERISTICS, &[1]),
+        (PRIMARIES, &[1]),
+      ]
+    );
+
+    let (_, audio) = children(tracks).unwrap()[1];
+    let delays = &children(audio).unwrap()[2..];
+    assert_eq!(
+      delays,
+      [
+        (CODEC_DELAY, &[0x63, 0x2E, 0xA0][..]),
+        (SEEK_PRE_ROLL, &[0x04, 0xC4, 0xB4, 0x00])
+      ]
+    );
+
+    let (last, _) = children(tracks).unwrap().pop().unwrap();
+    assert_eq!(last, VOID);
+  }
+
+  #[test]
+  fn placeholder_is_voided_when_the_tracks_are_not_found() {
+    let (file, tracks) = file();
+    let extras = Extras {
+      colorspace: Some(ColorSpace::Bt601),
+      audio_delays: None,
+    };
+    let mut writer = HeaderWriter::new(Cursor::new(Vec::new()), extras);
+
+    // an unexpected seek before the end of the tracks
+    writer.write_all(&file[..tracks.end - 1]).unwrap();
+    writer.seek(SeekFrom::End(0)).unwrap();
+    writer.write_all(&file[tracks.end - 1..]).unwrap();
+    let written = writer.inner.into_inner();
+
+    assert_eq!(written.len(), file.len());
+    let entry = find(&written[tracks.clone()], &[TRACK_ENTRY]);
+    let ids: Vec<_> = entry.iter().map(|&(id, _)| id).collect();
+    assert_eq!(ids, [TRACK_NUMBER, CODEC_ID, VIDEO, VOID]);
+  }
+
+  #[test]
+  fn later_writes_go_through() {
+    let (file, tracks) = file();
+    let extras = Extras {
+      colorspace: None,
+      audio_delays: None,
+    };
+    let mut writer = HeaderWriter::new(Cursor::new(Vec::new()), extras);
+
+    writer.write_all(&file).unwrap();
+    writer.seek(SeekFrom::Start(4)).unwrap();
+    writer.write_all(&[0xFF]).unwrap();
+    let written = writer.inner.into_inner();
+
+    assert_eq!(written.len(), file.len());
+    assert_eq!(written[4], 0xFF);
+    assert!(
+      find(&written[tracks.clone()], &[TRACK_ENTRY, VIDEO, COLOUR])
+        .iter()
+        .all(|&(id, _)| id == RANGE)
+    );
+  }
+}
@@ -0,0 +1,168 @@
+//! Global hotkeys bound to actions
+//!
+//! Hotkeys are written as `Ctrl+Alt+F10`: any number of modifiers (`Ctrl`,
+//! `Alt`, `Shift`, `Super`) followed by one key. The keys are X11 keysyms.
+
+use std::{fmt, str::FromStr};
+
+use clap::ValueEnum;
+use hotkey::modifiers;
+
+use crate::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Action {
+  /// Stop recording
+  Stop,
+  /// Save the replay buffer into a new file
+  SaveReplay,
+  /// Pause the recording, or resume it when paused
+  Pause,
+  /// Remember the current time of the recording
+  Bookmark,
+  /// Save the next captured frame as a PNG
+  Screenshot,
+}
+
+impl fmt::Display for Action {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let string = match self {
+      Self::Stop => "stop",
+      Self::SaveReplay => "save-replay",
+      Self::Pause => "pause",
+      Self::Bookmark => "bookmark",
+      Self::Screenshot => "screenshot",
+    };
+    write!(f, "{string}")
+  }
+}
+
+/// Key with its modifiers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotkey {
+  modifiers: u32,
+  key: u32,
+}
+
+impl FromStr for Hotkey {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut names: Vec<_> = s.split('+').map(str::trim).collect();
+    let key = names.pop().unwrap_or_default();
+
+    let mut hotkey = Self {
+      modifiers: 0,
+      key: keysym(key).ok_or_else(|| format!("Unknown key `{key}` in `{s}`"))?,
+    };
+
+    for name in names {
+      hotkey.modifiers |= match name.to_ascii_lowercase().as_str() {
+        "ctrl" | "control" => modifiers::CONTROL,
+        "alt" => modifiers::ALT,
+        "shift" => modifiers::SHIFT,
+        "super" | "win" => modifiers::SUPER,
+        _ => return Err(format!("Unknown modifier `{name}` in `{s}`")),
+      };
+    }
+
+    Ok(hotkey)
+  }
+}
+
+/// X11 keysym of the key
+fn keysym(name: &str) -> Option<u32> {
+  let name = name.to_ascii_lowercase();
+
+  if let Some(number) = name.strip_prefix('f').and_then(|n| n.parse::<u32>().ok()) {
+    return (1..=35).contains(&number).then_some(0xFFBE + number - 1);
+  }
+
+  // the keysyms of letters and digits are their ASCII codes
+  if let [c] = name.as_bytes() {
+    return c.is_ascii_alphanumeric().then_some(*c as u32);
+  }
+
+  let key = match name.as_str() {
+    "space" => 0x20,
+    "backspace" => 0xFF08,
+    "tab" => 0xFF09,
+    "return" | "enter" => 0xFF0D,
+    "pause" => 0xFF13,
+    "scrolllock" => 0xFF14,
+    "escape" | "esc" => 0xFF1B,
+    "home" => 0xFF50,
+    "left" => 0xFF51,
+    "up" => 0xFF52,
+    "right" => 0xFF53,
+    "down" => 0xFF54,
+    "pageup" => 0xFF55,
+    "pagedown" => 0xFF56,
+    "end" => 0xFF57,
+    "print" => 0xFF61,
+    "insert" => 0xFF63,
+    "delete" => 0xFFFF,
+    _ => return None,
+  };
+
+  Some(key)
+}
+
+/// Hotkey bound to an action, written as `ACTION=HOTKEY`
+#[derive(Debug, Clone)]
+pub struct Binding {
+  pub action: Action,
+  hotkey: Hotkey,
+  /// The hotkey as written by the user, for the messages
+  text: String,
+}
+
+impl Binding {
+  pub fn parse(s: &str) -> Result<Self, String> {
+    let Some((action, hotkey)) = s.split_once('=') else {
+      return Err(format!("Expected ACTION=HOTKEY, got `{s}`"));
+    };
+
+    Ok(Self {
+      action: Action::from_str(action.trim(), true)
+        .map_err(|_| format!("Unknown action `{action}` in `{s}`"))?,
+      hotkey: hotkey.parse()?,
+      text: hotkey.trim().to_owned(),
+    })
+  }
+}
+
+impl fmt::Display for Binding {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}={}", self.action, self.text)
+  }
+}
+
+/// Register the hotkeys and call `handle` whenever one is pressed
+///
+/// The hotkeys that can't be registered, e.g. because another program has
+/// grabbed them already, are reported and skipped. Blocks as long as any
+/// hotkey is registered.
+pub fn listen(bindings: &[Binding], handle: impl Fn(Action) + Clone + Send + 'static) {
+  let mut listener = hotkey::Listener::new();
+  let mut registered = 0;
+
+  for binding in bindings {
+    let action = binding.action;
+    let handle = handle.clone();
+
+    match listener.register_hotkey(binding.hotkey.modifiers, binding.hotkey.key, move || {
+      handle(action);
+    }) {
+      Ok(_) => registered += 1,
+      Err(e) => error(format!(
+        "Can't register {} for {}: {e}",
+        binding.text, binding.action
+      )),
+    }
+  }
+
+  if registered > 0 {
+    listener.listen();
+  }
+}
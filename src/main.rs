@@ -1,6 +1,6 @@
 //! Record a movie of your screen
 //!
-//! This simple utility records a movie (video only, no audio) of your screen.
+//! This simple utility records a movie of your screen, optionally with audio.
 //!
 //! It it based entirely on code from [srs](), and has been maintained as an
 //! example application to test the [vpx-encode]() library.
@@ -18,55 +18,391 @@
 //!
 //! Don't forget to install `libvpx`.
 //!
+//! # Usage
+//!
+//! `shadowplay record` records the screen into a file until ⏎ or SHIFT+F12 is
+//! pressed. `shadowplay displays` lists the displays that can be picked with
+//! `--display`, and `shadowplay probe <FILE>` describes a recorded file.
+//!
+//! Ctrl-C, SIGTERM and SIGQUIT finish the file like ⏎ does. Sending a second
+//! one exits right away, leaving the file unfinished.
+//!
+//! The file is synced to the disk at every keyframe, so a crash or a power
+//! loss costs only the last few seconds. Such a file has no duration and
+//! players can't seek in it. `shadowplay repair <FILE>` muxes the frames again
+//! into a finished `<FILE>-repaired.webm`, `--into` picks another name.
+//!
+//! `--display 0,2` records several displays at once, and `--display all` every
+//! one of them. Each display gets its own capturer, encoder and file, the
+//! audio goes into the file of the first one. Put `{display}` into
+//! `--filename` to tell the files apart. Stopping, pausing and saving the replay
+//! act on all displays together.
+//!
+//! # Instant replay
+//!
+//! `shadowplay replay <SECONDS>` keeps only the last N seconds. Press ⏎ or
+//! SHIFT+F11 to save them into a new file, while the recording keeps running.
+//!
+//! The buffer is kept on disk in the temporary folder (`--tmp`) as short
+//! keyframe-aligned parts, so even long replays don't eat up RAM. Use
+//! `--buffer memory` to keep it in RAM instead.
+//!
+//! # Daemon
+//!
+//! `shadowplay daemon record` and `shadowplay daemon replay <SECONDS>` run
+//! without a terminal and wait for commands on a Unix socket in
+//! `$XDG_RUNTIME_DIR`. They take the same options as the normal modes, and
+//! `shadowplay ctl <COMMAND>` controls them:
+//!
+//! - `start` starts a recording
+//! - `stop` stops it and finishes the file
+//! - `save-replay` saves the replay buffer
+//! - `pause` pauses the recording, or resumes it
+//! - `bookmark` and `screenshot` do the same as the hotkeys
+//! - `status` tells whether the daemon is recording
+//!
+//! A signal finishes the running recording and stops the daemon.
+//!
+//! Without a terminal, the first display is recorded unless `--display` picks
+//! others.
+//!
+//! # Hotkeys
+//!
+//! While recording in a terminal, global hotkeys control the recording.
+//! SHIFT+F12 stops it and SHIFT+F11 saves the replay by default. `--hotkey`
+//! binds other ones, and can be repeated:
+//!
+//! ```sh
+//! shadowplay replay 60 --hotkey save-replay=Ctrl+Alt+F10 --hotkey bookmark=Super+B
+//! ```
+//!
+//! The actions are `stop`, `save-replay`, `pause` (or resume), `bookmark`, which
+//! lists the time of the recording at the end, and `screenshot`, which saves the
+//! next frame as a PNG named after `--screenshot-filename`. In the config file
+//! the hotkeys are an array, `hotkey = []` disables them. A hotkey that can't
+//! be registered, e.g. because another program has grabbed it, is reported and
+//! skipped.
+//!
+//! # File names
+//!
+//! The files are named after the time they are created,
+//! `2023-01-31_18-30-00.webm`. `--filename <TEMPLATE>` changes that, using
+//! strftime specifiers (`%Y`, `%H`, ...) and these placeholders:
+//!
+//! - `{n}` is the first number that gives a new file, `{n:3}` pads it to 3 digits
+//! - `{display}` is the index of the recorded display
+//! - `{codec}` is `vp8` or `vp9`
+//! - `{width}` and `{height}` are the size of the video
+//!
+//! Existing files are never overwritten, a number is appended to the name
+//! instead. The template may contain folders, `%Y/%m/{n:3}.webm` sorts the
+//! clips by month.
+//!
+//! # Config file
+//!
+//! The defaults of the options can be changed in
+//! `$XDG_CONFIG_HOME/shadowplay/config.toml`, using the names of the long
+//! options. The flags still override them. Named profiles override the
+//! top-level settings when picked with `--profile <NAME>`:
+//!
+//! ```toml
+//! codec = "vp9"
+//! bv = 8000
+//! output = "/home/me/Videos"
+//!
+//! [profiles.stream]
+//! bv = 3000
+//! fps = 30
+//! ```
+//!
+//! `shadowplay config show` prints the settings in effect.
+//!
+//! # Capture sources
+//!
+//! The screen is captured with `scrap` by default, `--source captrs` uses
+//! `captrs` instead. `--source test` records a generated test pattern, so
+//! the whole pipeline can run without a display. The sources may number the
+//! displays differently, `shadowplay displays --source captrs` lists them as
+//! `captrs` sees them.
+//!
+//! `--region X,Y,WIDTH,HEIGHT` records only a rectangle of the display, the
+//! video then has the size of the rectangle.
+//!
+//! `--scale 1280x720` scales the video, `--scale 1280x` or `--scale x720` keep
+//! the aspect ratio, with the missing side rounded to an even number. The pixels
+//! are blended with `--scale-filter bilinear` by default, `area` is sharper when
+//! shrinking a lot and `nearest` is the fastest.
+//!
+//! # Audio
+//!
+//! `--audio pulse` records the monitor of the default output through
+//! PulseAudio (or PipeWire), another source can be picked with
+//! `--audio-input`. `--audio wav --audio-input <FILE>` plays a 16-bit WAV
+//! file in real time instead, sampled at 8, 12, 16, 24 or 48 kHz. The audio is
+//! encoded with Opus, don't forget to install `libopus`.
+//!
 //! # Video Format
 //!
-//! The video is stored as a WebM file.
+//! The video is stored as a WebM file, with an Opus audio track if recorded.
+//!
+//! The colours are converted using BT.709 in limited range by default, which is
+//! what players expect for HD video. `--colorspace bt601` and `--range full`
+//! change that. The colour matrix, the transfer characteristics and the range
+//! are marked in the file, VP9 streams mark them in the video too.
+//!
+//! `--bv` sets the target bitrate of the default `--rate-control vbr`. `cbr`
+//! keeps it constant, which suits streaming, and `cq` aims for the quality set
+//! by `--cq-level` (0 to 63, lower is better), using the bitrate as the upper
+//! limit. `--min-q` and `--max-q` bound the quantizer in every mode.
+//!
+//! The speed of the encoder is set by `--cpu-used`, higher is faster and worse,
+//! and `--deadline`, where `realtime` is the only one that keeps up with a
+//! live recording. `--encode-threads` picks the number of threads, and VP9
+//! spreads the work over them better with `--row-mt` and `--tile-columns`.
+//! `--lag-in-frames` lets the encoder look ahead, at the cost of latency.
+//! Conflicting settings are rejected before the recording starts:
+//!
+//! ```sh
+//! shadowplay record --codec vp9 --rate-control cq --cq-level 30 --row-mt --tile-columns 2
+//! ```
+//!
+//! `--keyint` caps the distance between keyframes, in frames (`--keyint 120`)
+//! or in seconds (`--keyint 2s`), which works with a variable frame rate too.
+//! Saved replays can only start at a keyframe, so a shorter interval makes them
+//! start closer to the requested length, at the cost of some bitrate. The disk
+//! replay buffer also asks the encoder for a keyframe whenever one of its parts
+//! is full.
+//!
+//! # Exit codes
+//!
+//! When something fails, the reason is printed and the exit code tells what
+//! went wrong, so scripts can react to it:
+//!
+//! - 1: the daemon can't be reached or refused the command
+//! - 2: invalid options or config file
+//! - 3: the screen can't be captured
+//! - 4: the audio can't be recorded
+//! - 5: the frames can't be converted
+//! - 6: the video can't be encoded
+//! - 7: the WebM file can't be written
+//! - 8: another file or the terminal can't be used
+//! - 9: a part of the recorder has crashed
+//! - 130: interrupted by a second signal
+//!
+//! A recording that fails midway is still finished, so the file stays playable.
 //!
 //! # Contributing
 //!
 //! All contributions are appreciated.
-#![feature(array_chunks)]
 #![feature(is_some_and)]
 #![warn(clippy::pedantic)]
 #![allow(clippy::cast_lossless)]
 #![allow(clippy::cast_possible_truncation)]
+#![allow(clippy::cast_possible_wrap)]
 #![allow(clippy::cast_precision_loss)]
 #![allow(clippy::cast_sign_loss)]
 #![allow(clippy::doc_markdown)]
 
+mod audio;
+mod capture;
+mod config;
+mod control;
 mod convert;
+mod encoder;
+mod exit;
+mod filename;
+mod header;
+mod hotkeys;
+mod output;
+mod pipeline;
+mod probe;
+mod repair;
+mod replay;
+mod scale;
+mod schedule;
+mod screenshot;
+mod yuv;
 
 use std::{
-  env, fmt,
-  fs::{File, OpenOptions},
+  env, fmt, fs,
   io::{self, Write},
   ops::Deref,
-  path::PathBuf,
+  panic::{self, AssertUnwindSafe},
+  path::{Path, PathBuf},
+  process,
   sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    mpsc, Arc, Mutex,
   },
   thread,
-  time::{Duration, Instant},
+  time::Duration,
 };
 
-use clap::{Parser, ValueEnum};
+use audio::{AudioFormat, AudioKind, AudioSource, PulseSource, WavSource};
+use capture::{
+  CaptrsSource, CaptureSource, Cropped, PixelFormat, Region, ScrapSource, SourceKind, TestPattern,
+};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use config::Config;
+use control::Request;
+use convert::Converter;
+use encoder::{Codec, Deadline, Keyint, RateControl, Tuning};
+use exit::Error;
+use filename::{Fields, Namer, Template};
+use hotkeys::{Action, Binding};
+use output::{Format, Target};
+use pipeline::{Pipeline, Stats};
 use quest::Boxes;
-use scrap::{Capturer, Display};
-use webm::{mux, mux::Track};
+use replay::{DiskBuffer, MemoryBuffer, Packet, ReplayBuffer};
+use scale::{Filter, Scaler, Size};
+use schedule::{Clock, Scheduler};
+use scrap::Display;
+use screenshot::Geometry;
+use signal_hook::{consts::TERM_SIGNALS, iterator::Signals};
+use webm::mux;
+use yuv::{ColorSpace, Range};
+
+/// How long to wait for a new frame when recording without a frame rate
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How often to check whether a paused recording was resumed
+const PAUSE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Exit code after a second signal, which doesn't wait for the files
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+  #[command(subcommand)]
+  command: Command,
+
+  /// Profile of the config file to use
+  #[arg(long, global = true, value_name = "NAME")]
+  profile: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  #[command(flatten)]
+  Mode(Mode),
+  /// Wait in the background for commands on the control socket
+  Daemon {
+    #[command(subcommand)]
+    mode: Mode,
+  },
+  /// Send a command to the running daemon
+  Ctl { request: Request },
+  /// List the displays that can be recorded
+  Displays {
+    /// Capture source whose displays are listed
+    #[arg(short, long, default_value_t)]
+    source: SourceKind,
+  },
+  /// Describe a WebM file
+  Probe { file: PathBuf },
+  /// Finish a recording that was cut short, writing it into a new file
+  Repair {
+    file: PathBuf,
+    /// Where to write the repaired file [default: FILE-repaired.webm]
+    #[arg(short, long)]
+    into: Option<PathBuf>,
+  },
+  /// Manage the config file
+  Config {
+    #[command(subcommand)]
+    action: ConfigAction,
+  },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+  /// Print the settings in effect, with the config file and the profile applied
+  Show,
+}
+
+// What to do with the captured frames. Not a doc comment, because it would
+// replace the description of the whole command.
+#[derive(Subcommand, Debug)]
+enum Mode {
+  /// Record everything into a file
+  Record(RecordArgs),
+  /// Keep only the last seconds and save them on demand
+  ///
+  /// The capture keeps running in the background. Press ⏎ or the
+  /// save-replay hotkey to save the buffer into a new file in the output
+  /// folder.
+  Replay(ReplayArgs),
+}
+
+#[derive(Args, Debug)]
+struct RecordArgs {
   /// Output folder
   ///
   /// Defaults to "~/Videos/shadowplay.rs/" or "C:/Users/<user>/Videos/shadowplay.rs"
   #[arg(short, long)]
   output: Option<PathBuf>,
 
-  /// Folder to use for temporary files
-  #[arg(short = 'm', long)]
-  tmp: Option<PathBuf>,
+  /// Name of the files, with strftime specifiers and placeholders
+  ///
+  /// The placeholders are {n}, {display}, {codec}, {width} and {height}. {n}
+  /// is the first number that gives a new file, {n:3} pads it to 3 digits.
+  /// Existing files are never overwritten, a number is appended to the name
+  /// instead.
+  #[arg(
+    long,
+    value_name = "TEMPLATE",
+    default_value = filename::DEFAULT_TEMPLATE,
+    value_parser = Template::parse
+  )]
+  filename: Template,
+
+  /// Name of the screenshots, like --filename
+  #[arg(
+    long,
+    value_name = "TEMPLATE",
+    default_value = filename::DEFAULT_SCREENSHOT_TEMPLATE,
+    value_parser = Template::parse
+  )]
+  screenshot_filename: Template,
+
+  /// Global hotkey bound to an action, can be repeated
+  ///
+  /// The actions are stop, save-replay, pause, bookmark and screenshot. The
+  /// hotkeys are modifiers (Ctrl, Alt, Shift, Super) and a key joined with
+  /// "+", e.g. "pause=Ctrl+Alt+F10".
+  #[arg(
+    long = "hotkey",
+    value_name = "ACTION=HOTKEY",
+    default_values = ["stop=Shift+F12", "save-replay=Shift+F11"],
+    value_parser = Binding::parse
+  )]
+  hotkeys: Vec<Binding>,
+
+  /// Where to capture the frames from
+  #[arg(short, long, default_value_t)]
+  source: SourceKind,
+
+  /// Indices of the displays to record, e.g. "0,2", or "all"
+  ///
+  /// Each display is recorded into its own file. [default: ask, or the first
+  /// one in the daemon]
+  #[arg(short, long, value_name = "INDICES", value_parser = DisplayArg::parse)]
+  display: Option<DisplayArg>,
+
+  /// Record only this rectangle of the display, in pixels
+  #[arg(long, value_name = "X,Y,WIDTH,HEIGHT", value_parser = Region::parse)]
+  region: Option<Region>,
+
+  /// Scale the video to this size, e.g. 1280x720
+  ///
+  /// Leaving out one side keeps the aspect ratio, "1280x" or "x720".
+  #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = Size::parse)]
+  scale: Option<Size>,
+
+  /// How to compute the pixels when scaling
+  #[arg(long, default_value_t)]
+  scale_filter: Filter,
 
   /// Codec to use when saving
   #[arg(short, long, default_value_t)]
@@ -76,7 +412,10 @@ struct Cli {
   #[arg(short, long)]
   time: Option<u64>,
 
-  /// Frames per second
+  /// Constant frame rate of the video [default: variable, as fast as possible]
+  ///
+  /// The last frame is repeated when the screen hasn't changed, and frames
+  /// are skipped when the capture falls behind.
   #[arg(short, long)]
   fps: Option<u64>,
 
@@ -84,242 +423,888 @@ struct Cli {
   #[arg(short, long, default_value_t = 5000)]
   bv: u32,
 
+  /// Longest distance between keyframes, in frames or seconds like "2s"
+  /// [default: libvpx's]
+  ///
+  /// Replays and repaired files can only start at a keyframe.
+  #[arg(long, value_name = "FRAMES|SECONDS", value_parser = Keyint::parse)]
+  keyint: Option<Keyint>,
+
+  /// Colour matrix of the video
+  #[arg(long, default_value_t)]
+  colorspace: ColorSpace,
+
+  /// Range of the YUV values in the video
+  #[arg(long, default_value_t)]
+  range: Range,
+
+  /// Number of threads converting the frames [default: half of the CPU cores]
+  ///
+  /// Each thread converts a horizontal band of the frame.
+  #[arg(long, value_name = "THREADS")]
+  convert_threads: Option<usize>,
+
+  /// How the encoder manages the bitrate
+  #[arg(long, default_value_t)]
+  rate_control: RateControl,
+
+  /// Quality of `--rate-control cq`, lower is better
+  #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u32).range(0..=63))]
+  cq_level: Option<u32>,
+
+  /// Speed of the encoder, higher is faster and worse [default: libvpx's]
+  ///
+  /// -16 to 16 for VP8, -9 to 9 for VP9.
+  #[arg(long, value_name = "SPEED", allow_hyphen_values = true)]
+  cpu_used: Option<i32>,
+
+  /// How much time the encoder may spend on a frame
+  #[arg(long, default_value_t)]
+  deadline: Deadline,
+
+  /// Number of threads encoding the frames [default: half of the CPU cores]
+  #[arg(long, value_name = "THREADS")]
+  encode_threads: Option<u32>,
+
+  /// Encode rows of blocks in parallel, VP9 only
+  #[arg(long)]
+  row_mt: bool,
+
+  /// Split the frame into 2^N tile columns encoded in parallel, VP9 only
+  #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(0..=6))]
+  tile_columns: Option<u32>,
+
+  /// How many frames the encoder may hold back to plan ahead [default: libvpx's]
+  #[arg(long, value_name = "FRAMES", value_parser = clap::value_parser!(u32).range(0..=25))]
+  lag_in_frames: Option<u32>,
+
+  /// Lowest quantizer, the best quality the encoder may use
+  #[arg(long, value_name = "Q", value_parser = clap::value_parser!(u32).range(0..=63))]
+  min_q: Option<u32>,
+
+  /// Highest quantizer, the worst quality the encoder may use
+  #[arg(long, value_name = "Q", value_parser = clap::value_parser!(u32).range(0..=63))]
+  max_q: Option<u32>,
+
   /// Audio bitrate in kbps
   #[arg(short = 'a', long, default_value_t = 128)]
   ba: u32,
+
+  /// Where to record the audio from
+  #[arg(long, default_value_t)]
+  audio: AudioKind,
+
+  /// PulseAudio source to record or the WAV file to read
+  ///
+  /// Defaults to the monitor of the default output.
+  #[arg(long, value_name = "DEVICE|FILE")]
+  audio_input: Option<PathBuf>,
+}
+
+impl RecordArgs {
+  fn tuning(&self) -> Tuning {
+    Tuning {
+      rate_control: self.rate_control,
+      cq_level: self.cq_level,
+      cpu_used: self.cpu_used,
+      deadline: self.deadline,
+      threads: self
+        .encode_threads
+        .unwrap_or_else(|| convert::default_threads() as u32),
+      row_mt: self.row_mt,
+      tile_columns: self.tile_columns,
+      lag_in_frames: self.lag_in_frames,
+      min_quantizer: self.min_q,
+      max_quantizer: self.max_q,
+    }
+  }
+}
+
+#[derive(Args, Debug)]
+struct ReplayArgs {
+  /// How many seconds to keep
+  #[arg(value_name = "SECONDS")]
+  length: u64,
+
+  /// Where to keep the replay buffer
+  #[arg(long, default_value_t)]
+  buffer: BufferKind,
+
+  /// Folder to use for temporary files
+  ///
+  /// Defaults to "/tmp/shadowplay-rs/"
+  #[arg(short = 'm', long)]
+  tmp: Option<PathBuf>,
+
+  #[command(flatten)]
+  record: RecordArgs,
+}
+
+/// Displays picked on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DisplayArg {
+  All,
+  List(Vec<usize>),
+}
+
+impl DisplayArg {
+  fn parse(s: &str) -> Result<Self, String> {
+    if s == "all" {
+      return Ok(Self::All);
+    }
+
+    let indices: Vec<usize> = s
+      .split(',')
+      .map(|index| index.trim().parse())
+      .collect::<Result<_, _>>()
+      .map_err(|e| format!("Expected display indices or `all`, got `{s}`: {e}"))?;
+
+    if let Some(index) = indices
+      .iter()
+      .enumerate()
+      .find_map(|(i, index)| indices[..i].contains(index).then_some(index))
+    {
+      return Err(format!("Display {index} is listed twice in `{s}`"));
+    }
+
+    Ok(Self::List(indices))
+  }
 }
 
 #[derive(Debug, Clone, ValueEnum, Default)]
-enum Codec {
+enum BufferKind {
+  /// Keep everything in RAM, fine for short replays
+  Memory,
+  /// Keep the encoded video in the temporary folder
   #[default]
-  VP8,
-  VP9,
+  Disk,
 }
 
-impl fmt::Display for Codec {
+impl fmt::Display for BufferKind {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     let string = match self {
-      Self::VP8 => "vp8",
-      Self::VP9 => "vp9",
+      Self::Memory => "memory",
+      Self::Disk => "disk",
     };
     write!(f, "{string}")
   }
 }
 
+/// Handles to control a running recording
+#[derive(Clone)]
+struct Controls {
+  stop: Arc<AtomicBool>,
+  /// Number of replay saves requested, each display saves every one
+  save: Arc<AtomicU64>,
+  /// Number of screenshots requested, each display takes every one
+  screenshot: Arc<AtomicU64>,
+  clock: Arc<Clock>,
+  /// Times of the recording marked by the user
+  bookmarks: Arc<Mutex<Vec<Duration>>>,
+  /// Whether the recording keeps a replay buffer
+  replay: bool,
+}
+
+impl Controls {
+  fn new(replay: bool) -> Self {
+    Self {
+      stop: Arc::new(AtomicBool::new(false)),
+      save: Arc::new(AtomicU64::new(0)),
+      screenshot: Arc::new(AtomicU64::new(0)),
+      clock: Arc::new(Clock::new()),
+      bookmarks: Arc::new(Mutex::new(Vec::new())),
+      replay,
+    }
+  }
+
+  /// Carry out the action, returns the message for the user
+  fn act(&self, action: Action) -> Result<String, String> {
+    match action {
+      Action::Stop => {
+        self.stop.store(true, Ordering::Release);
+        Ok("Stopping".into())
+      }
+      Action::SaveReplay if !self.replay => Err("Not recording a replay".into()),
+      Action::SaveReplay => {
+        self.save.fetch_add(1, Ordering::AcqRel);
+        Ok("Saving the replay".into())
+      }
+      Action::Pause => {
+        if self.clock.toggle_pause() {
+          Ok("Paused".into())
+        } else {
+          Ok("Resumed".into())
+        }
+      }
+      Action::Bookmark => {
+        let time = self.clock.elapsed();
+        self.bookmarks.lock().unwrap().push(time);
+        Ok(format!("Bookmark at {}", timestamp(time)))
+      }
+      Action::Screenshot => {
+        self.screenshot.fetch_add(1, Ordering::AcqRel);
+        Ok("Taking a screenshot".into())
+      }
+    }
+  }
+}
+
 fn main() {
-  let args = Cli::parse();
-
-  let max_time = args.time.map(Duration::from_secs);
-
-  let path = args
-    .output
-    .map_or_else(
-      || {
-        let home = env::var("HOME").unwrap();
-        PathBuf::from(home)
-          .join("Videos/shadowplay.rs")
-          .canonicalize()
-          .expect("Default directory not found")
-      },
-      PathBuf::from,
-    )
-    .join("test.webm");
+  if let Err(e) = run() {
+    error(&e);
+    process::exit(e.exit_code());
+  }
+}
 
-  println!("{path:?}");
+fn run() -> exit::Result<()> {
+  let command = configured_command().map_err(Error::Config)?;
+
+  let matches = command.clone().get_matches();
+  let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+  match cli.command {
+    Command::Mode(mode) => {
+      let controls = Controls::new(matches!(mode, Mode::Replay(_)));
+      handle_signals({
+        let stop = controls.stop.clone();
+        move || stop.store(true, Ordering::Release)
+      })?;
+      record(&mode, &controls, true)?;
+    }
+    Command::Daemon { mode } => daemon(&mode)?,
+    Command::Ctl { request } => {
+      let message = control::send(request).map_err(Error::Control)?;
+      println!("{message}");
+    }
+    Command::Displays { source } => {
+      for name in display_names(&displays(source)?) {
+        println!("{name}");
+      }
+    }
+    Command::Probe { file } => {
+      let info = probe::probe(&file).map_err(|e| Error::Io(format!("Can't read {file:?}"), e))?;
+      println!("{info}");
+    }
+    Command::Repair { file, into } => {
+      let output = into.unwrap_or_else(|| repair::default_output(&file));
+      let info = repair::repair(&file, &output)
+        .map_err(|e| Error::Io(format!("Can't repair {file:?}"), e))?;
+      println!("Repaired into {output:?}\n{info}");
+    }
+    Command::Config {
+      action: ConfigAction::Show,
+    } => config::show(&command),
+  }
 
-  let tmp = args
-    .tmp
-    .map_or_else(|| PathBuf::from("/tmp/shadowplay-rs/part1"), PathBuf::from);
+  Ok(())
+}
 
-  let Some(display) = get_display() else { return; };
+/// Definition of the command line, with the settings of the config file as
+/// the defaults
+fn configured_command() -> Result<clap::Command, String> {
+  config::apply(Cli::command(), &Config::load()?, profile_arg().as_deref())
+}
 
-  // Setup the recorder.
-  let mut capturer = Capturer::new(display).expect("Can't initialize capturer");
-  let width = capturer.width();
-  let height = capturer.height();
+/// Value of `--profile`
+///
+/// The profile decides the defaults, so it's needed before the real parsing,
+/// which would also stop at `--help`.
+fn profile_arg() -> Option<String> {
+  let mut args = env::args_os().skip(1);
 
-  // Setup the multiplexer.
-  let Some(out) = get_output_file(&path) else { return; };
+  while let Some(arg) = args.next() {
+    let arg = arg.to_string_lossy();
 
-  let mut webm =
-    mux::Segment::new(mux::Writer::new(out)).expect("Could not initialize the multiplexer.");
+    if arg == "--" {
+      break;
+    } else if arg == "--profile" {
+      return args.next().map(|name| name.to_string_lossy().into_owned());
+    } else if let Some(name) = arg.strip_prefix("--profile=") {
+      return Some(name.to_owned());
+    }
+  }
+
+  None
+}
 
-  let (vpx_codec, mux_codec) = match args.codec {
-    Codec::VP8 => (vpx_encode::VideoCodecId::VP8, mux::VideoCodecId::VP8),
-    Codec::VP9 => (vpx_encode::VideoCodecId::VP9, mux::VideoCodecId::VP9),
+/// Record until stopped, printing the statistics at the end
+///
+/// In interactive mode the user is asked about the displays, and the
+/// recording is controlled from the terminal and the hotkeys. Every display
+/// is recorded into its own file or replay buffer, by its own pipeline. When
+/// one of them fails, the others are stopped too.
+fn record(mode: &Mode, controls: &Controls, interactive: bool) -> exit::Result<()> {
+  let (args, replay) = match mode {
+    Mode::Record(args) => (args, None),
+    Mode::Replay(replay) => (&replay.record, Some(replay)),
   };
 
-  let mut video_track = webm.add_video_track(width as u32, height as u32, None, mux_codec);
+  // probing the displays may be slow, so it's done only once
+  let available = displays(args.source)?;
+  let displays = match (args.source, &args.display) {
+    (SourceKind::Test, _) => vec![None],
+    (_, Some(DisplayArg::All)) => (0..available.len()).map(Some).collect(),
+    (_, Some(DisplayArg::List(indices))) => indices.iter().copied().map(Some).collect(),
+    (_, None) if interactive => get_displays(&available)?.into_iter().map(Some).collect(),
+    (_, None) => vec![Some(0)],
+  };
 
-  // Setup the encoder.
-  let mut vpx_encoder = vpx_encode::Encoder::new(vpx_encode::Config {
-    width: width as u32,
-    height: height as u32,
-    timebase: [1, 1000],
-    bitrate: args.bv,
-    codec: vpx_codec,
-  })
-  .expect("Can't initialize encoder");
+  // fail before anything starts
+  args.tuning().check(args.codec).map_err(Error::Config)?;
+
+  controls.clock.reset();
+
+  // Setup the audio, it runs on its own thread and goes with the first display.
+  let mut audio = open_audio_source(args.audio, args.audio_input.as_deref())?
+    .map(|source| {
+      audio::spawn(
+        source,
+        args.ba,
+        controls.clock.clone(),
+        controls.stop.clone(),
+      )
+    })
+    .transpose()
+    .map_err(Error::Audio)?;
 
   // Start recording.
-  let start = Instant::now();
-  let stop = Arc::new(AtomicBool::new(false));
+  if interactive {
+    thread::spawn({
+      let controls = controls.clone();
+      let bindings = args.hotkeys.clone();
+      move || listen_hotkeys(&bindings, controls)
+    });
 
-  thread::spawn({
-    let stop = stop.clone();
-    move || setup_hotkey(stop)
+    thread::spawn({
+      let controls = controls.clone();
+      let replay = replay.is_some();
+      move || read_input(replay, &controls.stop, &controls.save)
+    });
+  }
+
+  // the sources can't be moved between threads, so each is opened on its own
+  let results: Vec<_> = thread::scope(|scope| {
+    let recorders: Vec<_> = displays
+      .iter()
+      .map(|&display| {
+        let audio = audio.take();
+        let available = &available;
+        scope.spawn(move || {
+          // a crash stops the other displays too
+          let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            record_display(args, replay, display, available, audio, controls)
+          }))
+          .unwrap_or(Err(Error::Crash("The recording of a display")));
+          if result.is_err() {
+            controls.stop.store(true, Ordering::Release);
+          }
+          (display, result)
+        })
+      })
+      .collect();
+
+    recorders
+      .into_iter()
+      .zip(&displays)
+      .map(|(recorder, &display)| {
+        recorder
+          .join()
+          .unwrap_or((display, Err(Error::Crash("The recording of a display"))))
+      })
+      .collect()
   });
 
-  thread::spawn({
-    let stop = stop.clone();
-    move || {
-      quest::ask("Recording! Press ⏎ to stop.");
-      let _ = quest::text();
-      stop.store(true, Ordering::Release);
+  // the first failure is returned, the others are only reported
+  let mut failure = None;
+  for (display, result) in results {
+    match (result, display) {
+      (Ok(stats), Some(index)) if displays.len() > 1 => println!("Display {index}: {stats}"),
+      (Ok(stats), _) => println!("{stats}"),
+      (Err(e), _) if failure.is_none() => failure = Some(e),
+      (Err(e), _) => error(e),
     }
-  });
+  }
 
-  let mut file = File::create(tmp).expect("Can't open tmp file");
+  print_bookmarks(&controls.bookmarks.lock().unwrap());
 
-  let seconds_per_frame = args
-    .fps
-    .map(|fps| Duration::from_nanos(1_000_000_000 / fps));
+  failure.map_or(Ok(()), Err)
+}
 
-  while !stop.load(Ordering::Acquire) {
-    let now = Instant::now();
-    let time = now - start;
+/// Record one display until stopped, `available` are the sizes of the
+/// displays of the source
+fn record_display(
+  args: &RecordArgs,
+  replay: Option<&ReplayArgs>,
+  display: Option<usize>,
+  available: &[(usize, usize)],
+  audio: Option<(AudioFormat, mpsc::Receiver<Packet>)>,
+  controls: &Controls,
+) -> exit::Result<Arc<Stats>> {
+  let mut source = open_source(
+    args.source,
+    display.unwrap_or_default(),
+    available,
+    args.region,
+  )?;
+  let captured = (source.width(), source.height());
+  let (width, height) = args
+    .scale
+    .map_or(captured, |size| size.resolve(captured.0, captured.1));
+  let scaler = ((width, height) != captured)
+    .then(|| Scaler::new(args.scale_filter, captured, (width, height)));
 
-    if max_time.is_some_and(|d| time > d) {
+  if source.pixel_format() != PixelFormat::Bgra {
+    return Err(Error::Capture("Unsupported pixel format".into()));
+  }
+
+  let mux_codec = match args.codec {
+    Codec::VP8 => mux::VideoCodecId::VP8,
+    Codec::VP9 => mux::VideoCodecId::VP9,
+  };
+
+  let (audio_format, audio) = audio.unzip();
+
+  let format = Format {
+    width: width as u32,
+    height: height as u32,
+    codec: mux_codec,
+    colorspace: Some(args.colorspace),
+    range: args.range,
+    audio: audio_format,
+  };
+
+  // Setup the names of the files.
+  let folder = if let Some(folder) = &args.output {
+    folder.clone()
+  } else {
+    let home = env::var_os("HOME")
+      .filter(|home| !home.is_empty())
+      .ok_or_else(|| Error::Config("$HOME isn't set, pick the folder with --output".into()))?;
+    let folder = PathBuf::from(home).join("Videos/shadowplay.rs");
+    folder
+      .canonicalize()
+      .map_err(|e| Error::Io(format!("Can't use the default folder {folder:?}"), e))?
+  };
+  let fields = Fields {
+    display,
+    codec: args.codec.to_string(),
+    width: width as u32,
+    height: height as u32,
+  };
+  let screenshots = Namer::new(
+    folder.clone(),
+    args.screenshot_filename.clone(),
+    fields.clone(),
+  );
+
+  // Setup the conversion, encoder and multiplexer, the file is created last
+  // so a failed setup doesn't leave it behind.
+  let config = encoder::Config {
+    codec: args.codec,
+    width: width as u32,
+    height: height as u32,
+    bitrate: args.bv,
+    colorspace: args.colorspace,
+    range: args.range,
+    keyint: args.keyint,
+    tuning: args.tuning(),
+  };
+
+  let threads = args
+    .convert_threads
+    .unwrap_or_else(convert::default_threads);
+  let converter = Converter::new(threads, args.colorspace, args.range, scaler)?;
+
+  // Setup the output file or the replay buffer.
+  let namer = Namer::new(folder, args.filename.clone(), fields);
+  let target = open_target(replay, display.unwrap_or_default(), namer)?;
+
+  let save = controls.save.clone();
+  let mut pipeline = Pipeline::new(converter, config, target, format, audio, save);
+
+  let captured = capture(
+    &mut *source,
+    &mut pipeline,
+    controls,
+    &screenshots,
+    args.fps,
+    args.time.map(Duration::from_secs),
+  );
+
+  // Wait for remaining frames to complete, even when the capture failed
+  let stats = pipeline.finish();
+  captured.and(stats)
+}
+
+fn print_bookmarks(bookmarks: &[Duration]) {
+  if !bookmarks.is_empty() {
+    let times: Vec<_> = bookmarks.iter().copied().map(timestamp).collect();
+    println!("Bookmarks: {}", times.join(", "));
+  }
+}
+
+/// Capture frames and pass them into the pipeline until stopped
+///
+/// With a frame rate the frames are timestamped on its grid, repeating the
+/// last frame when there is no new one. Otherwise every new frame is taken as
+/// soon as it's available. Nothing is captured while the clock is paused.
+///
+/// A requested screenshot is taken from the next new frame.
+fn capture(
+  source: &mut dyn CaptureSource,
+  pipeline: &mut Pipeline,
+  controls: &Controls,
+  screenshots: &Namer,
+  fps: Option<u64>,
+  max_time: Option<Duration>,
+) -> exit::Result<()> {
+  let (width, height) = (source.width(), source.height());
+  let clock = &controls.clock;
+  let mut scheduler = fps.map(|fps| Scheduler::new(clock.clone(), fps));
+  let mut screenshots_taken = controls.screenshot.load(Ordering::Acquire);
+
+  // a stage that has stopped has reported why, and `finish` returns its error
+  while !controls.stop.load(Ordering::Acquire) && !pipeline.has_stopped() {
+    if max_time.is_some_and(|d| clock.elapsed() > d) {
       break;
     }
 
-    match capturer.frame() {
+    if clock.is_paused() {
+      thread::sleep(PAUSE_INTERVAL);
+      continue;
+    }
+
+    let tick = scheduler.as_mut().map(Scheduler::wait);
+    if let Some(tick) = tick {
+      pipeline.skip(tick.missed);
+    }
+
+    let pts = tick.map_or_else(|| clock.elapsed().as_millis() as u64, |tick| tick.pts);
+
+    match source.frame() {
       Ok(frame) => {
-        process_frame(
-          width,
-          height,
-          &frame,
-          &mut vpx_encoder,
-          time.as_millis(),
-          &mut video_track,
-        );
+        let requested = controls.screenshot.load(Ordering::Acquire);
+        if requested != screenshots_taken {
+          screenshots_taken = requested;
+          let geometry = Geometry {
+            width,
+            height,
+            stride: frame.stride,
+          };
+          screenshot::save(&frame, geometry, screenshots.clone());
+        }
+        pipeline.push(&frame, frame.stride, pts);
       }
-      Err(e) if e.kind() == io::ErrorKind::WouldBlock => { /* Wait */ }
-      Err(e) => {
-        println!("{e}");
-        break;
+      Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+        if tick.is_some() {
+          pipeline.repeat(pts);
+        } else {
+          // don't spin while waiting for a new frame
+          thread::sleep(POLL_INTERVAL);
+        }
       }
+      Err(e) => return Err(Error::Capture(format!("Can't capture the screen: {e}"))),
     }
+  }
+
+  Ok(())
+}
+
+/// Record on the requests coming through the control socket
+///
+/// A failed recording is reported, and the daemon waits for the next one.
+/// A signal finishes the running recording and ends the daemon.
+fn daemon(mode: &Mode) -> exit::Result<()> {
+  let listener =
+    control::bind().map_err(|e| Error::Io("Can't open the control socket".into(), e))?;
+
+  // controls of the running recording
+  let session = Arc::new(Mutex::new(None::<Controls>));
+  // `None` ends the daemon
+  let (start, sessions) = mpsc::channel();
 
-    if let Some(spf) = seconds_per_frame {
-      let dt = now.elapsed();
-      if dt < spf {
-        thread::sleep(spf - dt);
+  handle_signals({
+    let session = session.clone();
+    let start = start.clone();
+    move || {
+      if let Some(controls) = &*session.lock().unwrap() {
+        controls.stop.store(true, Ordering::Release);
       }
+      let _ = start.send(None);
     }
-  }
+  })?;
+
+  thread::spawn({
+    let session = session.clone();
+    let replay = matches!(mode, Mode::Replay(_));
+    move || {
+      control::serve(&listener, |request| {
+        handle_request(request, &session, &start, replay)
+      });
+    }
+  });
+
+  println!("Waiting for commands on {:?}", control::socket_path());
 
-  // Wait for remaining frames to complete
-  let mut frames = vpx_encoder.finish().expect("Can't finish encoding");
-  while let Some(frame) = frames.next().expect("Can't read frame") {
-    video_track.add_frame(frame.data, frame.pts as u64 * 1_000_000, frame.key);
+  while let Ok(Some(controls)) = sessions.recv() {
+    if let Err(e) = record(mode, &controls, false) {
+      error(e);
+    }
+    *session.lock().unwrap() = None;
   }
 
-  let _ = webm.finalize(None);
-}
-
-fn process_frame(
-  width: usize,
-  height: usize,
-  frame: &scrap::Frame,
-  vpx_encoder: &mut vpx_encode::Encoder,
-  millis: u128,
-  video_track: &mut mux::VideoTrack,
-) {
-  let start = Instant::now();
-  let yuv_frame = convert::argb_to_yuv420(width, height, frame);
-  // let yuv_frame = convert::argb_to_yuv420_with_subsampling(width, height, frame);
-  // let yuv_frame = convert::argb_to_yuv444(width, height, frame);
-  let elapsed = start.elapsed();
-  println!("{elapsed:?}");
-
-  // add frame to the encoding queue
-  let encoded = vpx_encoder
-    .encode(
-      millis as i64,
-      &yuv_frame,
-      vpx_encode::vpx_img_fmt::VPX_IMG_FMT_I444,
-    )
-    .expect("Can't encode frame");
-
-  // if there are any frames done encoding add them to the track
-  for encoded_frame in encoded {
-    video_track.add_frame(
-      encoded_frame.data,
-      encoded_frame.pts as u64 * 1_000_000,
-      encoded_frame.key,
-    );
+  let _ = fs::remove_file(control::socket_path());
+
+  Ok(())
+}
+
+/// Call `stop` on the first SIGINT, SIGTERM or SIGQUIT, and exit right away
+/// on the second one
+fn handle_signals(stop: impl FnOnce() + Send + 'static) -> exit::Result<()> {
+  let mut signals =
+    Signals::new(TERM_SIGNALS).map_err(|e| Error::Io("Can't handle the signals".into(), e))?;
+
+  thread::spawn(move || {
+    let mut signals = signals.forever();
+
+    if signals.next().is_some() {
+      println!("Finishing, send the signal again to exit right away");
+      stop();
+    }
+
+    if signals.next().is_some() {
+      process::exit(INTERRUPTED_EXIT_CODE);
+    }
+  });
+
+  Ok(())
+}
+
+/// Answer a request from `ctl`, `session` holds the controls of the running recording
+fn handle_request(
+  request: Request,
+  session: &Mutex<Option<Controls>>,
+  start: &mpsc::Sender<Option<Controls>>,
+  replay: bool,
+) -> Result<String, String> {
+  let mut session = session.lock().unwrap();
+
+  if request == Request::Start && session.is_none() {
+    let controls = Controls::new(replay);
+    if start.send(Some(controls.clone())).is_err() {
+      return Err("The daemon is shutting down".into());
+    }
+    *session = Some(controls);
+    return Ok("Recording".into());
   }
+
+  let Some(controls) = session.as_ref() else {
+    return match request {
+      Request::Status => Ok("Idle".into()),
+      _ => Err("Not recording".into()),
+    };
+  };
+
+  let action = match request {
+    Request::Start => return Err("Already recording".into()),
+    Request::Status => {
+      let paused = if controls.clock.is_paused() {
+        " (paused)"
+      } else {
+        ""
+      };
+      return Ok(format!(
+        "Recording for {} s{paused}",
+        controls.clock.elapsed().as_secs()
+      ));
+    }
+    Request::SaveReplay if !replay => {
+      return Err("Not recording a replay, start the daemon in replay mode".into())
+    }
+    Request::Stop => Action::Stop,
+    Request::SaveReplay => Action::SaveReplay,
+    Request::Pause => Action::Pause,
+    Request::Bookmark => Action::Bookmark,
+    Request::Screenshot => Action::Screenshot,
+  };
+
+  controls.act(action)
 }
 
-fn setup_hotkey(stop: Arc<AtomicBool>) {
-  let mut hk = hotkey::Listener::new();
-  hk.register_hotkey(hotkey::modifiers::SHIFT, 0xFFC9 /* F12 */, move || {
-    println!("Alt-F12 pressed!");
+/// Control the recording from stdin
+fn read_input(replay: bool, stop: &AtomicBool, save: &AtomicU64) {
+  if !replay {
+    quest::ask("Recording! Press ⏎ to stop.");
+    let _ = quest::text();
     stop.store(true, Ordering::Release);
-  })
-  .unwrap();
-  hk.listen();
+    return;
+  }
+
+  quest::ask("Recording! Press ⏎ to save the replay, q⏎ to stop.\n");
+  loop {
+    match quest::text() {
+      Ok(line) if line.trim() != "q" => {
+        save.fetch_add(1, Ordering::AcqRel);
+      }
+      _ => break,
+    }
+  }
+  stop.store(true, Ordering::Release);
 }
 
-fn get_output_file(path: &PathBuf) -> Option<File> {
-  let file = OpenOptions::new().write(true).create_new(true).open(path);
+/// Carry out the actions of the hotkeys, reporting the results
+fn listen_hotkeys(bindings: &[Binding], controls: Controls) {
+  hotkeys::listen(bindings, move |action| match controls.act(action) {
+    Ok(message) => println!("{message}"),
+    Err(message) => error(message),
+  });
+}
 
-  let out = match file {
-    Ok(file) => file,
-    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
-      let confirm = loop {
-        quest::ask("Overwrite the existing file? [y/N] ");
-        if let Some(b) = quest::yesno(false).expect("Can't read input") {
-          break b;
-        }
+fn open_target(replay: Option<&ReplayArgs>, display: usize, namer: Namer) -> exit::Result<Target> {
+  if let Some(replay) = replay {
+    let length = Duration::from_secs(replay.length);
+    let tmp = replay
+      .tmp
+      .clone()
+      .unwrap_or_else(|| PathBuf::from("/tmp/shadowplay-rs"));
+
+    let buffer: Box<dyn ReplayBuffer> = match replay.buffer {
+      BufferKind::Memory => Box::new(MemoryBuffer::new(length)),
+      BufferKind::Disk => {
+        // every display has its own buffer
+        let dir = DiskBuffer::dir(&tmp, display);
+        Box::new(
+          DiskBuffer::new(dir.clone(), length)
+            .map_err(|e| Error::Io(format!("Can't create the replay buffer in {dir:?}"), e))?,
+        )
+      }
+    };
+
+    return Ok(Target::Replay { buffer, namer });
+  }
+
+  let (path, file) = namer
+    .create()
+    .map_err(|e| Error::Io("Can't create the output file".into(), e))?;
+  println!("{path:?}");
+
+  Ok(Target::File { file, path })
+}
+
+fn open_audio_source(
+  kind: AudioKind,
+  input: Option<&Path>,
+) -> exit::Result<Option<Box<dyn AudioSource + Send>>> {
+  let source: Box<dyn AudioSource + Send> = match kind {
+    AudioKind::None => return Ok(None),
+    AudioKind::Pulse => {
+      let device = input.map(|path| path.to_string_lossy());
+      Box::new(PulseSource::new(device.as_deref()).map_err(Error::Audio)?)
+    }
+    AudioKind::Wav => {
+      let path =
+        input.ok_or_else(|| Error::Config("--audio wav needs the file in --audio-input".into()))?;
+      Box::new(
+        WavSource::new(path)
+          .map_err(|e| Error::Io(format!("Can't open the WAV file {path:?}"), e))?,
+      )
+    }
+  };
+
+  Ok(Some(source))
+}
+
+/// Open the display of the source, `available` are the sizes of its displays
+fn open_source(
+  kind: SourceKind,
+  display: usize,
+  available: &[(usize, usize)],
+  region: Option<Region>,
+) -> exit::Result<Box<dyn CaptureSource>> {
+  let capturer_error = |e| Error::Capture(format!("Can't initialize the capturer: {e}"));
+
+  let source: Box<dyn CaptureSource> = match kind {
+    SourceKind::Scrap => {
+      let all = Display::all().map_err(capturer_error)?;
+      let Some(display) = all.into_iter().nth(display) else {
+        return Err(Error::Capture(format!("No display {display}.")));
       };
 
-      if confirm {
-        File::create(path).expect("Can't create file")
-      } else {
-        return None;
+      Box::new(ScrapSource::new(display).map_err(capturer_error)?)
+    }
+    SourceKind::Captrs => {
+      if display >= available.len() {
+        return Err(Error::Capture(format!("No display {display}.")));
       }
+
+      Box::new(CaptrsSource::new(display).map_err(capturer_error)?)
+    }
+    SourceKind::Test => {
+      let (width, height) = TestPattern::SIZE;
+      Box::new(TestPattern::new(width, height))
     }
-    e => e.expect("Error opening file"),
   };
 
-  Some(out)
+  let Some(region) = region else { return Ok(source); };
+
+  Ok(Box::new(
+    Cropped::new(source, region).map_err(Error::Capture)?,
+  ))
 }
 
-fn get_display() -> Option<Display> {
-  let displays = Display::all().expect("Displays couldn't be initialized");
+/// Let the user choose the displays to record, returns their indices
+fn get_displays(available: &[(usize, usize)]) -> exit::Result<Vec<usize>> {
+  let mut names = display_names(available);
 
-  let i = if displays.is_empty() {
-    error("No displays found.");
-    return None;
-  } else if displays.len() == 1 {
-    0
+  let indices = if names.is_empty() {
+    return Err(Error::Capture("No displays found.".into()));
+  } else if names.len() == 1 {
+    vec![0]
   } else {
-    let names: Vec<_> = displays
-      .iter()
-      .enumerate()
-      .map(|(i, display)| format!("Display {} [{}x{}]", i, display.width(), display.height(),))
-      .collect();
+    let count = names.len();
+    names.push("All displays".into());
 
     quest::ask("Which display?\n");
-    let i = quest::choose(Boxes::default(), &names).expect("Can't read input");
+    let i = quest::choose(Boxes::default(), &names)
+      .map_err(|e| Error::Io("Can't read the input".into(), e))?;
     println!();
 
-    i
+    if i == count {
+      (0..count).collect()
+    } else {
+      vec![i]
+    }
   };
 
-  displays.into_iter().nth(i)
+  Ok(indices)
+}
+
+/// Sizes of the displays of the source, in the order of their indices
+fn displays(kind: SourceKind) -> exit::Result<Vec<(usize, usize)>> {
+  kind
+    .displays()
+    .map_err(|e| Error::Capture(format!("Displays couldn't be initialized: {e}")))
+}
+
+/// Descriptions of the displays with the given sizes
+fn display_names(displays: &[(usize, usize)]) -> Vec<String> {
+  displays
+    .iter()
+    .enumerate()
+    .map(|(i, (width, height))| format!("Display {i} [{width}x{height}]"))
+    .collect()
+}
+
+/// `HH:MM:SS.mmm`
+fn timestamp(time: Duration) -> String {
+  let seconds = time.as_secs();
+  format!(
+    "{:02}:{:02}:{:02}.{:03}",
+    seconds / 3600,
+    seconds / 60 % 60,
+    seconds % 60,
+    time.subsec_millis()
+  )
 }
 
 fn error<S: fmt::Display>(s: S) {
@@ -0,0 +1,435 @@
+use std::{
+  collections::VecDeque,
+  fs::{self, File},
+  io,
+  path::{Path, PathBuf},
+  sync::mpsc::{self, TrySendError},
+  thread,
+};
+
+use webm::{mux, mux::Track};
+
+use crate::{
+  audio::{self, AudioFormat},
+  error,
+  exit::{self, Error},
+  filename::Namer,
+  header::{self, Extras, HeaderWriter},
+  replay::{Packet, Packets, ReplayBuffer, TrackKind},
+  yuv::{ColorSpace, Range},
+};
+
+type Segment = mux::Segment<mux::Writer<HeaderWriter<File>>>;
+
+/// Parameters of the tracks in the output
+#[derive(Debug, Clone)]
+pub struct Format {
+  pub width: u32,
+  pub height: u32,
+  pub codec: mux::VideoCodecId,
+  /// Left unmarked when it isn't known
+  pub colorspace: Option<ColorSpace>,
+  pub range: Range,
+  pub audio: Option<AudioFormat>,
+}
+
+/// Most audio packets held back while waiting for the video, 10 s of Opus
+/// frames
+const MAX_AUDIO_QUEUE: usize = 500;
+
+/// Tracks of an open WebM file
+struct Tracks {
+  video: mux::VideoTrack,
+  audio: Option<mux::AudioTrack>,
+}
+
+impl Tracks {
+  /// Start a new file with the tracks of `format`
+  fn open(out: File, format: &Format) -> Option<(Segment, Self)> {
+    let extras = Extras {
+      colorspace: format.colorspace,
+      audio_delays: (format.audio.as_ref())
+        .map(|audio| (audio.codec_delay(), audio::SEEK_PRE_ROLL)),
+    };
+    let mut webm = mux::Segment::new(mux::Writer::new(HeaderWriter::new(out, extras)))?;
+
+    let mut video = webm.add_video_track(format.width, format.height, None, format.codec);
+
+    // The frames are always 8-bit 4:2:0. The crate only marks the range, the
+    // rest of the colours is added by the header writer in place of the
+    // placeholder.
+    video.set_color(8, (true, true), format.range == Range::Full);
+    webm.set_codec_private(video.track_number(), &header::ROOM);
+
+    let audio = format.audio.as_ref().map(|audio| {
+      let track = webm.add_audio_track(
+        audio.sample_rate as i32,
+        audio.channels as i32,
+        None,
+        mux::AudioCodecId::Opus,
+      );
+      webm.set_codec_private(track.track_number(), &audio.codec_private);
+      track
+    });
+
+    Some((webm, Self { video, audio }))
+  }
+
+  fn add(&mut self, packet: &Packet, start: u64) -> bool {
+    let timestamp = (packet.pts - start) * 1_000_000;
+
+    match (packet.kind, &mut self.audio) {
+      (TrackKind::Video, _) => self.video.add_frame(&packet.data, timestamp, packet.key),
+      (TrackKind::Audio, Some(audio)) => audio.add_frame(&packet.data, timestamp, packet.key),
+      (TrackKind::Audio, None) => true,
+    }
+  }
+}
+
+/// Where to write the encoded frames
+///
+/// The multiplexer can't be moved between threads, so it's only set up from
+/// this by [`Output::new`] on the thread that does the muxing.
+pub enum Target {
+  /// Record everything into this file, which is removed again if nothing
+  /// gets recorded into it
+  File { file: File, path: PathBuf },
+  /// Keep only the last few seconds and save them into new files on request
+  Replay {
+    buffer: Box<dyn ReplayBuffer>,
+    namer: Namer,
+  },
+}
+
+/// Where the interleaved packets end up
+enum Sink {
+  /// Record everything straight into a single file
+  ///
+  /// The muxer starts a new cluster at every video keyframe, and writes the
+  /// frames as they come. The file is synced to the disk at each keyframe, so
+  /// after a crash at most the last cluster is lost and `repair` can finish
+  /// the rest.
+  File {
+    webm: Segment,
+    tracks: Tracks,
+    syncer: Syncer,
+    path: PathBuf,
+    /// Whether any video frame was written
+    recorded: bool,
+  },
+  /// Keep only the last few seconds and save them on request
+  Replay {
+    buffer: Box<dyn ReplayBuffer>,
+    namer: Namer,
+  },
+}
+
+/// Syncs a file to the disk on its own thread, so the muxing never waits for
+/// the disk
+struct Syncer {
+  requests: mpsc::SyncSender<()>,
+  thread: thread::JoinHandle<()>,
+}
+
+impl Syncer {
+  fn new(file: File) -> Self {
+    // a single request can wait while the previous sync is running
+    let (requests, receiver) = mpsc::sync_channel(1);
+
+    let thread = thread::spawn(move || {
+      for () in receiver {
+        if let Err(e) = file.sync_data() {
+          error(format!("Can't sync the file: {e}"));
+        }
+      }
+    });
+
+    Self { requests, thread }
+  }
+
+  /// Sync everything written so far, unless a sync is already waiting
+  fn request(&self) {
+    if let Err(TrySendError::Disconnected(())) = self.requests.try_send(()) {
+      error("Can't sync the file, the syncing thread has stopped");
+    }
+  }
+
+  fn finish(self) {
+    drop(self.requests);
+    let _ = self.thread.join();
+  }
+}
+
+/// Destination of the encoded frames
+///
+/// The muxer needs the packets of all tracks ordered by their timestamps, but
+/// audio arrives from its own thread. Packets are therefore held back until
+/// it is known that no older packet can come from the other track.
+pub struct Output {
+  sink: Sink,
+  format: Format,
+  video: VecDeque<Packet>,
+  audio: VecDeque<Packet>,
+  /// Whether to still wait for audio packets
+  audio_running: bool,
+  /// Whether audio is being dropped because the video doesn't come
+  dropping_audio: bool,
+}
+
+impl Output {
+  pub fn new(target: Target, format: Format) -> exit::Result<Self> {
+    let sink = match target {
+      Target::File { file: out, path } => {
+        let opened = out
+          .try_clone()
+          .map_err(|e| Error::Io("Can't open the output file".into(), e))
+          .and_then(|file| {
+            let (webm, tracks) = Tracks::open(out, &format).ok_or_else(|| {
+              Error::Mux(format!("Could not initialize the multiplexer for {path:?}"))
+            })?;
+            Ok((webm, tracks, Syncer::new(file)))
+          });
+
+        match opened {
+          Ok((webm, tracks, syncer)) => Sink::File {
+            webm,
+            tracks,
+            syncer,
+            path,
+            recorded: false,
+          },
+          Err(e) => {
+            remove(&path);
+            return Err(e);
+          }
+        }
+      }
+      Target::Replay { buffer, namer } => Sink::Replay { buffer, namer },
+    };
+
+    Ok(Self {
+      sink,
+      audio_running: format.audio.is_some(),
+      dropping_audio: false,
+      format,
+      video: VecDeque::new(),
+      audio: VecDeque::new(),
+    })
+  }
+
+  pub fn add_video(&mut self, packet: Packet) -> exit::Result<()> {
+    self.video.push_back(packet);
+    self.flush()
+  }
+
+  /// Queue an audio packet, dropping the oldest one when the video has
+  /// stalled for too long
+  pub fn add_audio(&mut self, packet: Packet) -> exit::Result<()> {
+    if self.audio.len() >= MAX_AUDIO_QUEUE {
+      self.audio.pop_front();
+      if !self.dropping_audio {
+        error("No video for too long, dropping the audio");
+        self.dropping_audio = true;
+      }
+    }
+
+    self.audio.push_back(packet);
+    self.flush()
+  }
+
+  /// Stop waiting for audio, e.g. because the source has ended
+  pub fn end_audio(&mut self) -> exit::Result<()> {
+    self.audio_running = false;
+    self.flush()
+  }
+
+  /// Write out all packets that can't be preceded by any packet to come
+  fn flush(&mut self) -> exit::Result<()> {
+    loop {
+      let packet = match (self.video.front(), self.audio.front()) {
+        (Some(video), Some(audio)) if audio.pts < video.pts => self.audio.pop_front(),
+        (Some(_), Some(_)) => self.video.pop_front(),
+        (Some(_), None) if !self.audio_running => self.video.pop_front(),
+        _ => None,
+      };
+
+      let Some(packet) = packet else { return Ok(()); };
+      self.dropping_audio &= packet.kind == TrackKind::Audio;
+      self.write(packet)?;
+    }
+  }
+
+  fn write(&mut self, packet: Packet) -> exit::Result<()> {
+    match &mut self.sink {
+      Sink::File {
+        tracks,
+        syncer,
+        path,
+        recorded,
+        ..
+      } => {
+        if !tracks.add(&packet, 0) {
+          let kind = match packet.kind {
+            TrackKind::Video => "video",
+            TrackKind::Audio => "audio",
+          };
+          return Err(Error::Mux(format!(
+            "Can't add the {kind} frame at {} ms into {path:?}",
+            packet.pts
+          )));
+        }
+        *recorded |= packet.kind == TrackKind::Video;
+
+        // the keyframe has started a new cluster, so the previous one is
+        // complete
+        if packet.is_video_key() {
+          syncer.request();
+        }
+      }
+      Sink::Replay { buffer, .. } => {
+        if let Err(e) = buffer.push(packet) {
+          error(format!("Can't write into the replay buffer: {e}"));
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Whether the replay buffer is waiting for a video keyframe to start a
+  /// new part
+  pub fn wants_keyframe(&self) -> bool {
+    match &self.sink {
+      Sink::Replay { buffer, .. } => buffer.wants_keyframe(),
+      Sink::File { .. } => false,
+    }
+  }
+
+  /// Write the current content of the replay buffer into a new file
+  ///
+  /// The file is written on a separate thread, so the recording isn't
+  /// interrupted.
+  pub fn save_replay(&mut self) {
+    let Sink::Replay { buffer, namer } = &mut self.sink else { return; };
+
+    let mut packets = match buffer.snapshot() {
+      Ok(packets) => packets.peekable(),
+      Err(e) => {
+        error(format!("Can't read the replay buffer: {e}"));
+        return;
+      }
+    };
+
+    // don't create a file that would stay empty
+    if packets.peek().is_none() {
+      error("Nothing to save yet.");
+      return;
+    }
+    let packets: Packets = Box::new(packets);
+
+    let namer = namer.clone();
+    let format = self.format.clone();
+
+    thread::spawn(move || {
+      let result = namer.create().and_then(|(path, out)| {
+        let saved = save_clip(out, packets, &format);
+        // a partly written clip would look like a whole one
+        if saved.is_err() {
+          remove(&path);
+        }
+        saved.map(|()| path)
+      });
+
+      match result {
+        Ok(path) => println!("Replay saved to {path:?}"),
+        Err(e) => error(format!("Can't save the replay: {e}")),
+      }
+    });
+  }
+
+  /// Write the rest of the packets and finalize the file
+  ///
+  /// The file is finalized even if a packet can't be written.
+  pub fn finish(mut self) -> exit::Result<()> {
+    // nothing else is coming, so the rest can be written as it is
+    let written = self.end_audio().and_then(|()| {
+      while let Some(packet) = self.video.pop_front().or_else(|| self.audio.pop_front()) {
+        self.write(packet)?;
+      }
+      Ok(())
+    });
+
+    match self.sink {
+      Sink::File {
+        webm,
+        syncer,
+        path,
+        recorded,
+        ..
+      } => {
+        syncer.finish();
+        let finalized = webm
+          .finalize(None)
+          .map(|_| ())
+          .map_err(|_| Error::Mux(format!("Could not finalize {path:?}")));
+
+        // e.g. the encoder couldn't be set up, the file would be unplayable
+        if !recorded {
+          remove(&path);
+        }
+
+        written.and(finalized)
+      }
+      // the replay is only saved on request
+      Sink::Replay { .. } => written,
+    }
+  }
+}
+
+/// Remove a file that is empty or only partly written
+pub fn remove(path: &Path) {
+  if let Err(e) = fs::remove_file(path) {
+    error(format!("Can't remove the unusable file {path:?}: {e}"));
+  }
+}
+
+/// Write the packets into a new WebM file with timestamps starting from zero
+///
+/// The packets are copied as they are, without re-encoding.
+pub fn save_clip(out: File, packets: Packets, format: &Format) -> io::Result<()> {
+  write_clip(out, packets, format, true)
+}
+
+/// Write the packets into a new WebM file, keeping their timestamps
+pub fn remux(out: File, packets: Packets, format: &Format) -> io::Result<()> {
+  write_clip(out, packets, format, false)
+}
+
+fn write_clip(out: File, packets: Packets, format: &Format, rebase: bool) -> io::Result<()> {
+  let (webm, mut tracks) = Tracks::open(out, format)
+    .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Could not initialize the multiplexer"))?;
+
+  let mut start = None;
+
+  for packet in packets {
+    let packet = packet?;
+
+    // audio captured slightly before the first keyframe is dropped
+    let start = if rebase {
+      *start.get_or_insert(packet.pts)
+    } else {
+      0
+    };
+    if packet.pts < start {
+      continue;
+    }
+
+    tracks.add(&packet, start);
+  }
+
+  webm
+    .finalize(None)
+    .map_err(|_| io::Error::new(io::ErrorKind::Other, "Could not finalize the clip"))?;
+
+  Ok(())
+}
@@ -0,0 +1,421 @@
+use std::{
+  fmt,
+  sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    mpsc, Arc,
+  },
+  thread::{self, JoinHandle},
+  time::{Duration, Instant},
+};
+
+use crate::{
+  convert::Converter,
+  encoder::{self, Encoder},
+  error,
+  exit::{self, Error},
+  output::{Format, Output, Target},
+  replay::{Packet, TrackKind},
+  yuv::{PlaneLayout, YuvFrame},
+};
+
+/// How many frames can wait between two stages before new ones get dropped
+const QUEUE_LENGTH: usize = 2;
+
+/// How often to complain about dropped frames
+const DROP_WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Frame passed between the stages
+struct Frame<T> {
+  /// `None` repeats the previous frame
+  data: Option<T>,
+  /// Timestamp in milliseconds since the start of the recording
+  pts: u64,
+}
+
+/// Captured pixels waiting for the conversion
+struct Raw {
+  data: Vec<u8>,
+  /// Length of one row in bytes
+  stride: usize,
+}
+
+/// Counters shared by all stages of the pipeline
+#[derive(Debug, Default)]
+pub struct Stats {
+  pub captured: AtomicU64,
+  /// Frames repeated because the capture had nothing new
+  pub duplicated: AtomicU64,
+  /// Frames skipped because the capture fell behind the frame rate
+  pub missed: AtomicU64,
+  pub converted: AtomicU64,
+  /// Frames dropped because the conversion couldn't keep up
+  pub dropped_capture: AtomicU64,
+  /// Frames dropped because the encoder couldn't keep up
+  pub dropped_convert: AtomicU64,
+  pub encoded: AtomicU64,
+  /// Total time spent converting the frames, in microseconds
+  pub convert_time: AtomicU64,
+}
+
+impl Stats {
+  pub fn dropped(&self) -> u64 {
+    self.missed.load(Ordering::Relaxed)
+      + self.dropped_capture.load(Ordering::Relaxed)
+      + self.dropped_convert.load(Ordering::Relaxed)
+  }
+}
+
+impl fmt::Display for Stats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let captured = self.captured.load(Ordering::Relaxed);
+    let duplicated = self.duplicated.load(Ordering::Relaxed);
+    let missed = self.missed.load(Ordering::Relaxed);
+    let dropped_capture = self.dropped_capture.load(Ordering::Relaxed);
+    let dropped_convert = self.dropped_convert.load(Ordering::Relaxed);
+    let encoded = self.encoded.load(Ordering::Relaxed);
+    let converted = self.converted.load(Ordering::Relaxed);
+    let convert_time = self.convert_time.load(Ordering::Relaxed) / converted.max(1);
+
+    write!(
+      f,
+      "Captured {captured} frames, repeated {duplicated} and skipped {missed} to keep the frame \
+       rate, encoded {encoded}. Dropped {dropped_capture} waiting for conversion and \
+       {dropped_convert} waiting for the encoder. Average conversion took {convert_time} µs."
+    )
+  }
+}
+
+/// Capture → convert → encode → mux pipeline
+///
+/// Every stage after the capture runs on its own thread, connected by short
+/// bounded queues. When a stage can't keep up, the raw frames waiting for it
+/// are dropped and counted, so the capture never stalls. Encoded frames are
+/// never dropped.
+pub struct Pipeline {
+  frames: Option<mpsc::SyncSender<Frame<Raw>>>,
+  recycled: mpsc::Receiver<Vec<u8>>,
+  stats: Arc<Stats>,
+  threads: Vec<JoinHandle<exit::Result<()>>>,
+  last_warning: Instant,
+  last_dropped: u64,
+}
+
+impl Pipeline {
+  /// The encoder and the output are created on their own threads, because
+  /// they can't be moved between threads once created
+  pub fn new(
+    converter: Converter,
+    config: encoder::Config,
+    target: Target,
+    format: Format,
+    audio: Option<mpsc::Receiver<Packet>>,
+    save: Arc<AtomicU64>,
+  ) -> Self {
+    let stats = Arc::new(Stats::default());
+    // set by the muxer when it needs a keyframe, taken by the encoder
+    let keyframe = Arc::new(AtomicBool::new(false));
+    let width = config.width as usize;
+    let height = config.height as usize;
+
+    let (frames, raw) = mpsc::sync_channel(QUEUE_LENGTH);
+    let (recycle, recycled) = mpsc::channel();
+    let (to_encoder, yuv) = mpsc::sync_channel(QUEUE_LENGTH);
+    let (encoded, packets) = mpsc::sync_channel::<Packet>(QUEUE_LENGTH * 16);
+
+    let convert = thread::spawn({
+      let stats = stats.clone();
+      move || {
+        convert_stage(
+          &converter,
+          width,
+          height,
+          &raw,
+          &recycle,
+          &to_encoder,
+          &stats,
+        );
+        Ok(())
+      }
+    });
+
+    let encode = thread::spawn({
+      let stats = stats.clone();
+      let keyframe = keyframe.clone();
+      move || encode_stage(config, &yuv, &encoded, &keyframe, &stats)
+    });
+
+    let mux = thread::spawn(move || {
+      mux_stage(
+        Output::new(target, format)?,
+        &packets,
+        audio.as_ref(),
+        &save,
+        &keyframe,
+      )
+    });
+
+    Self {
+      frames: Some(frames),
+      recycled,
+      stats,
+      threads: vec![convert, encode, mux],
+      last_warning: Instant::now(),
+      last_dropped: 0,
+    }
+  }
+
+  /// Whether a stage has stopped, so no more frames can be recorded
+  pub fn has_stopped(&self) -> bool {
+    self.frames.is_none()
+  }
+
+  /// Queue a captured frame, dropping it if the conversion is still busy
+  pub fn push(&mut self, data: &[u8], stride: usize, pts: u64) {
+    if self.frames.is_none() {
+      return;
+    }
+
+    self.stats.captured.fetch_add(1, Ordering::Relaxed);
+
+    let mut buffer = self.recycled.try_recv().unwrap_or_default();
+    buffer.clear();
+    buffer.extend_from_slice(data);
+
+    self.send(Frame {
+      data: Some(Raw {
+        data: buffer,
+        stride,
+      }),
+      pts,
+    });
+  }
+
+  /// Queue a repetition of the previous frame
+  pub fn repeat(&mut self, pts: u64) {
+    self.stats.duplicated.fetch_add(1, Ordering::Relaxed);
+    self.send(Frame { data: None, pts });
+  }
+
+  /// Count frames that were never captured because the capture was late
+  pub fn skip(&mut self, count: u64) {
+    self.stats.missed.fetch_add(count, Ordering::Relaxed);
+  }
+
+  fn send(&mut self, frame: Frame<Raw>) {
+    let Some(frames) = &self.frames else { return; };
+
+    match frames.try_send(frame) {
+      Ok(()) => {}
+      Err(mpsc::TrySendError::Full(_)) => {
+        self.stats.dropped_capture.fetch_add(1, Ordering::Relaxed);
+      }
+      Err(mpsc::TrySendError::Disconnected(_)) => {
+        error("The conversion stage has stopped");
+        self.frames = None;
+      }
+    }
+
+    self.warn_dropped();
+  }
+
+  fn warn_dropped(&mut self) {
+    if self.last_warning.elapsed() < DROP_WARNING_INTERVAL {
+      return;
+    }
+
+    let dropped = self.stats.dropped();
+    if dropped > self.last_dropped {
+      error(format!(
+        "Dropped {} frames in the last {} s, the system can't keep up",
+        dropped - self.last_dropped,
+        self.last_warning.elapsed().as_secs()
+      ));
+    }
+
+    self.last_warning = Instant::now();
+    self.last_dropped = dropped;
+  }
+
+  /// Encode and write all queued frames and wait for the stages to finish
+  ///
+  /// Fails with the error of the first stage that failed.
+  pub fn finish(mut self) -> exit::Result<Arc<Stats>> {
+    // closing the first queue stops the stages one by one
+    self.frames = None;
+
+    let mut result = Ok(());
+    for thread in self.threads.drain(..) {
+      match thread.join() {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => result = result.and(Err(e)),
+        Err(_) => result = result.and(Err(Error::Crash("A stage of the pipeline"))),
+      }
+    }
+
+    result.map(|()| self.stats)
+  }
+}
+
+fn convert_stage(
+  converter: &Converter,
+  width: usize,
+  height: usize,
+  raw: &mpsc::Receiver<Frame<Raw>>,
+  recycle: &mpsc::Sender<Vec<u8>>,
+  to_encoder: &mpsc::SyncSender<Frame<YuvFrame>>,
+  stats: &Stats,
+) {
+  for frame in raw {
+    // repeats need no conversion
+    let data = frame.data.map(|raw| {
+      let start = Instant::now();
+      let data = converter.argb_to_yuv420(width, height, &raw.data, raw.stride);
+      let elapsed = start.elapsed().as_micros() as u64;
+      stats.convert_time.fetch_add(elapsed, Ordering::Relaxed);
+      stats.converted.fetch_add(1, Ordering::Relaxed);
+
+      // the capture may be gone already
+      let _ = recycle.send(raw.data);
+
+      data
+    });
+
+    match to_encoder.try_send(Frame {
+      data,
+      pts: frame.pts,
+    }) {
+      Ok(()) => {}
+      Err(mpsc::TrySendError::Full(_)) => {
+        stats.dropped_convert.fetch_add(1, Ordering::Relaxed);
+      }
+      Err(mpsc::TrySendError::Disconnected(_)) => break,
+    }
+  }
+}
+
+fn encode_stage(
+  config: encoder::Config,
+  yuv: &mpsc::Receiver<Frame<YuvFrame>>,
+  encoded: &mpsc::SyncSender<Packet>,
+  keyframe: &AtomicBool,
+  stats: &Stats,
+) -> exit::Result<()> {
+  let mut vpx_encoder = Encoder::new(&config, PlaneLayout::I420)?;
+
+  let send = |frame: encoder::Frame| {
+    stats.encoded.fetch_add(1, Ordering::Relaxed);
+
+    encoded
+      .send(Packet {
+        kind: TrackKind::Video,
+        data: frame.data.to_vec(),
+        pts: frame.pts as u64,
+        key: frame.key,
+      })
+      .is_ok()
+  };
+
+  // kept around for the repeats
+  let mut last = None;
+
+  for frame in yuv {
+    if frame.data.is_some() {
+      last = frame.data;
+    }
+
+    // nothing to repeat before the first frame
+    let Some(data) = &last else { continue; };
+
+    if keyframe.swap(false, Ordering::AcqRel) {
+      vpx_encoder.force_keyframe();
+    }
+
+    // add frame to the encoding queue
+    let packets = vpx_encoder.encode(frame.pts as i64, data)?;
+
+    // if there are any frames done encoding pass them to the muxer
+    for packet in packets {
+      if !send(packet) {
+        return Ok(());
+      }
+    }
+  }
+
+  // Wait for remaining frames to complete
+  let mut frames = vpx_encoder.finish();
+  while let Some(frame) = frames.next()? {
+    if !send(frame) {
+      return Ok(());
+    }
+  }
+
+  Ok(())
+}
+
+fn mux_stage(
+  mut output: Output,
+  packets: &mpsc::Receiver<Packet>,
+  audio: Option<&mpsc::Receiver<Packet>>,
+  save: &AtomicU64,
+  keyframe: &AtomicBool,
+) -> exit::Result<()> {
+  let muxed = mux_packets(&mut output, packets, audio, save, keyframe);
+
+  // what was written is finished even after a failure, so it stays playable
+  let finished = output.finish();
+  muxed.and(finished)
+}
+
+/// Pass the packets into the output until the encoder is done
+fn mux_packets(
+  output: &mut Output,
+  packets: &mpsc::Receiver<Packet>,
+  audio: Option<&mpsc::Receiver<Packet>>,
+  save: &AtomicU64,
+  keyframe: &AtomicBool,
+) -> exit::Result<()> {
+  // the saves requested before the recording started are done already
+  let mut saved = save.load(Ordering::Acquire);
+  let mut keyframe_requested = false;
+
+  loop {
+    match packets.recv_timeout(Duration::from_millis(50)) {
+      Ok(packet) => output.add_video(packet)?,
+      Err(mpsc::RecvTimeoutError::Timeout) => {}
+      Err(mpsc::RecvTimeoutError::Disconnected) => break,
+    }
+
+    if let Some(receiver) = audio {
+      receive_audio(receiver, output)?;
+    }
+
+    let requested = save.load(Ordering::Acquire);
+    if requested != saved {
+      saved = requested;
+      output.save_replay();
+    }
+
+    // asked once, until the keyframe reaches the buffer
+    let wanted = output.wants_keyframe();
+    if wanted && !keyframe_requested {
+      keyframe.store(true, Ordering::Release);
+    }
+    keyframe_requested = wanted;
+  }
+
+  match audio {
+    Some(receiver) => receive_audio(receiver, output),
+    None => Ok(()),
+  }
+}
+
+/// Pass the audio packets captured so far into the output
+fn receive_audio(receiver: &mpsc::Receiver<Packet>, output: &mut Output) -> exit::Result<()> {
+  loop {
+    match receiver.try_recv() {
+      Ok(packet) => output.add_audio(packet)?,
+      Err(mpsc::TryRecvError::Empty) => return Ok(()),
+      Err(mpsc::TryRecvError::Disconnected) => return output.end_audio(),
+    }
+  }
+}
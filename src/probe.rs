@@ -0,0 +1,575 @@
+//! Minimal reader of WebM files, enough to describe the ones we write
+//!
+//! Only the elements needed for the summary are decoded, everything else is
+//! skipped. Elements of unknown size, left behind when the muxer didn't get to
+//! finish the file, are supported. The frames can be read too, so that such a
+//! file can be muxed again.
+
+use std::{
+  fmt,
+  fs::File,
+  io::{self, BufReader, Read, Seek, SeekFrom},
+  path::Path,
+};
+
+const EBML: u32 = 0x1A45_DFA3;
+const DOC_TYPE: u32 = 0x4282;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_A966;
+const TIMECODE_SCALE: u32 = 0x2A_D7B1;
+const DURATION: u32 = 0x4489;
+const MUXING_APP: u32 = 0x4D80;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const COLOUR: u32 = 0x55B0;
+const MATRIX_COEFFICIENTS: u32 = 0x55B1;
+const RANGE: u32 = 0x55B9;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMECODE: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const BLOCK_GROUP: u32 = 0xA0;
+const BLOCK: u32 = 0xA1;
+const REFERENCE_BLOCK: u32 = 0xFB;
+
+/// Children of a segment, they end a cluster of unknown size
+const TOP_LEVEL: [u32; 8] = [
+  INFO,
+  TRACKS,
+  CLUSTER,
+  0x114D_9B74, // SeekHead
+  0x1C53_BB6B, // Cues
+  0x1254_C367, // Tags
+  0x1043_A770, // Chapters
+  0x1941_A469, // Attachments
+];
+
+/// Summary of a WebM file
+#[derive(Debug)]
+pub struct FileInfo {
+  pub doc_type: String,
+  pub muxing_app: String,
+  /// Nanoseconds per timestamp unit
+  pub timecode_scale: u64,
+  /// Duration written by the muxer, in timestamp units
+  pub duration: Option<f64>,
+  pub tracks: Vec<TrackInfo>,
+  pub clusters: u64,
+  /// The muxer didn't finish the file: the sizes were never written or it
+  /// ends in the middle of an element
+  pub unfinished: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct TrackInfo {
+  pub number: u64,
+  /// 1 for video, 2 for audio
+  pub kind: u64,
+  pub codec: String,
+  pub codec_private: Vec<u8>,
+  pub width: u64,
+  pub height: u64,
+  /// 1 for limited, 2 for full range, 0 when not marked
+  pub range: u64,
+  /// Colour matrix as defined by ITU-T H.273, 0 when not marked
+  pub matrix: u64,
+  pub sample_rate: f64,
+  pub channels: u64,
+  pub frames: u64,
+  pub keyframes: u64,
+  /// Timestamp of the last frame in milliseconds
+  pub last_pts: Option<u64>,
+}
+
+impl FileInfo {
+  /// Duration in milliseconds, estimated from the frames when the muxer
+  /// didn't write it
+  pub fn duration(&self) -> Option<u64> {
+    let written = self
+      .duration
+      .map(|duration| (duration * self.timecode_scale as f64 / 1_000_000.) as u64);
+
+    written.or_else(|| self.tracks.iter().filter_map(|track| track.last_pts).max())
+  }
+
+  fn count(&mut self, number: u64, pts: u64, key: bool) {
+    let Some(track) = self.tracks.iter_mut().find(|track| track.number == number) else { return; };
+
+    track.frames += 1;
+    track.keyframes += key as u64;
+    track.last_pts = Some(track.last_pts.map_or(pts, |last| last.max(pts)));
+  }
+}
+
+impl fmt::Display for FileInfo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Format: {}, muxed by {}", self.doc_type, self.muxing_app)?;
+
+    match self.duration() {
+      Some(duration) if self.duration.is_some() => {
+        writeln!(f, "Duration: {:.3} s", duration as f64 / 1000.)?;
+      }
+      Some(duration) => writeln!(
+        f,
+        "Duration: {:.3} s, estimated from the frames",
+        duration as f64 / 1000.
+      )?,
+      None => writeln!(f, "Duration: unknown")?,
+    }
+
+    for track in &self.tracks {
+      write!(f, "Track {}: {}", track.number, track.codec)?;
+      match track.kind {
+        1 => write!(f, ", {}x{}", track.width, track.height)?,
+        2 => write!(f, ", {} Hz, {} channels", track.sample_rate, track.channels)?,
+        _ => {}
+      }
+      writeln!(
+        f,
+        ", {} frames ({} keyframes)",
+        track.frames, track.keyframes
+      )?;
+    }
+
+    write!(f, "Clusters: {}", self.clusters)?;
+
+    if self.unfinished {
+      write!(f, "\nThe file wasn't finished properly")?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Frame of a track, as stored in a block
+#[derive(Debug)]
+pub struct Frame {
+  pub track: u64,
+  /// Timestamp in milliseconds
+  pub pts: u64,
+  pub key: bool,
+  pub data: Vec<u8>,
+}
+
+type OnFrame<'a> = dyn FnMut(&FileInfo, Frame) -> io::Result<()> + 'a;
+
+/// Read the structure of a WebM file
+pub fn probe(path: &Path) -> io::Result<FileInfo> {
+  read(path, None)
+}
+
+/// Read the structure of a WebM file and pass every frame to `on_frame`, in
+/// the order they are stored
+///
+/// The tracks are known by the time the first frame is passed. A file that
+/// wasn't finished is read up to the last complete frame.
+pub fn frames(
+  path: &Path,
+  mut on_frame: impl FnMut(&FileInfo, Frame) -> io::Result<()>,
+) -> io::Result<FileInfo> {
+  read(path, Some(&mut on_frame))
+}
+
+fn read(path: &Path, mut on_frame: Option<&mut OnFrame<'_>>) -> io::Result<FileInfo> {
+  let file = File::open(path)?;
+  let length = file.metadata()?.len();
+  let mut reader = Reader {
+    inner: BufReader::new(file),
+    position: 0,
+    length,
+  };
+
+  let mut info = FileInfo {
+    doc_type: String::new(),
+    muxing_app: String::new(),
+    timecode_scale: 1_000_000,
+    duration: None,
+    tracks: Vec::new(),
+    clusters: 0,
+    unfinished: false,
+  };
+
+  let header = reader.header()?;
+  if header.id != EBML {
+    return Err(invalid("Not a WebM file"));
+  }
+
+  let end = reader.end(&header);
+  while reader.position < end {
+    let element = reader.header()?;
+    match element.id {
+      DOC_TYPE => info.doc_type = reader.string(&element)?,
+      _ => reader.skip(&element)?,
+    }
+  }
+
+  match read_segment(&mut reader, &mut info, &mut on_frame) {
+    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => info.unfinished = true,
+    result => result?,
+  }
+
+  Ok(info)
+}
+
+fn read_segment<R: Read + Seek>(
+  reader: &mut Reader<R>,
+  info: &mut FileInfo,
+  on_frame: &mut Option<&mut OnFrame<'_>>,
+) -> io::Result<()> {
+  let segment = reader.header()?;
+  if segment.id != SEGMENT {
+    return Err(invalid("The file has no segment"));
+  }
+
+  let end = reader.end(&segment);
+  info.unfinished = segment.size.map_or(true, |_| end > reader.length);
+
+  while reader.position < end.min(reader.length) {
+    let element = reader.header()?;
+    match element.id {
+      INFO => read_info(reader, &element, info)?,
+      TRACKS => read_tracks(reader, &element, info)?,
+      CLUSTER => read_cluster(reader, &element, info, on_frame)?,
+      _ => reader.skip(&element)?,
+    }
+  }
+
+  Ok(())
+}
+
+fn read_info<R: Read + Seek>(
+  reader: &mut Reader<R>,
+  header: &Header,
+  info: &mut FileInfo,
+) -> io::Result<()> {
+  let end = reader.end(header);
+  while reader.position < end {
+    let element = reader.header()?;
+    match element.id {
+      TIMECODE_SCALE => info.timecode_scale = reader.uint(&element)?,
+      DURATION => info.duration = Some(reader.float(&element)?),
+      MUXING_APP => info.muxing_app = reader.string(&element)?,
+      _ => reader.skip(&element)?,
+    }
+  }
+
+  Ok(())
+}
+
+fn read_tracks<R: Read + Seek>(
+  reader: &mut Reader<R>,
+  header: &Header,
+  info: &mut FileInfo,
+) -> io::Result<()> {
+  let end = reader.end(header);
+  while reader.position < end {
+    let element = reader.header()?;
+    if element.id != TRACK_ENTRY {
+      reader.skip(&element)?;
+      continue;
+    }
+
+    let mut track = TrackInfo::default();
+    let entry_end = reader.end(&element);
+    while reader.position < entry_end {
+      let element = reader.header()?;
+      match element.id {
+        TRACK_NUMBER => track.number = reader.uint(&element)?,
+        TRACK_TYPE => track.kind = reader.uint(&element)?,
+        CODEC_ID => track.codec = reader.string(&element)?,
+        CODEC_PRIVATE => track.codec_private = reader.data(&element)?,
+        // the video and audio settings have no common ids, so they can share
+        // the loop
+        VIDEO | AUDIO | COLOUR => {}
+        PIXEL_WIDTH => track.width = reader.uint(&element)?,
+        PIXEL_HEIGHT => track.height = reader.uint(&element)?,
+        RANGE => track.range = reader.uint(&element)?,
+        MATRIX_COEFFICIENTS => track.matrix = reader.uint(&element)?,
+        SAMPLING_FREQUENCY => track.sample_rate = reader.float(&element)?,
+        CHANNELS => track.channels = reader.uint(&element)?,
+        _ => reader.skip(&element)?,
+      }
+    }
+
+    info.tracks.push(track);
+  }
+
+  Ok(())
+}
+
+fn read_cluster<R: Read + Seek>(
+  reader: &mut Reader<R>,
+  header: &Header,
+  info: &mut FileInfo,
+  on_frame: &mut Option<&mut OnFrame<'_>>,
+) -> io::Result<()> {
+  let keep_data = on_frame.is_some();
+  info.clusters += 1;
+
+  let mut timecode = 0;
+  let end = reader.end(header);
+
+  while reader.position < end.min(reader.length) {
+    let element = reader.header()?;
+    match element.id {
+      // an unknown size ends with the next cluster
+      id if header.size.is_none() && TOP_LEVEL.contains(&id) => {
+        reader.seek(element.offset)?;
+        break;
+      }
+      TIMECODE => timecode = reader.uint(&element)?,
+      SIMPLE_BLOCK => {
+        let block = read_block(reader, &element, timecode, info, keep_data)?;
+        let key = block.flags & 0x80 != 0;
+        add_frame(info, on_frame, block, key)?;
+      }
+      BLOCK_GROUP => {
+        let mut block = None;
+        // blocks in groups have no keyframe flag, they are keyframes unless
+        // they reference another block
+        let mut key = true;
+
+        let group_end = reader.end(&element);
+        while reader.position < group_end {
+          let element = reader.header()?;
+          match element.id {
+            BLOCK => block = Some(read_block(reader, &element, timecode, info, keep_data)?),
+            REFERENCE_BLOCK => {
+              key = false;
+              reader.skip(&element)?;
+            }
+            _ => reader.skip(&element)?,
+          }
+        }
+
+        if let Some(block) = block {
+          add_frame(info, on_frame, block, key)?;
+        }
+      }
+      _ => reader.skip(&element)?,
+    }
+  }
+
+  Ok(())
+}
+
+/// Block read from a cluster
+struct Block {
+  track: u64,
+  /// Timestamp in milliseconds
+  pts: u64,
+  flags: u8,
+  /// The frame, unless it was skipped
+  data: Option<Vec<u8>>,
+}
+
+/// Read the track number, the timestamp and the flags of a block, and the
+/// frame when `keep_data` is set
+fn read_block<R: Read + Seek>(
+  reader: &mut Reader<R>,
+  header: &Header,
+  timecode: u64,
+  info: &FileInfo,
+  keep_data: bool,
+) -> io::Result<Block> {
+  let (number, length) = reader.vint()?;
+  let track = number ^ (1 << (7 * length));
+
+  let mut bytes = [0; 3];
+  reader.read(&mut bytes)?;
+  let relative = i16::from_be_bytes([bytes[0], bytes[1]]);
+  let flags = bytes[2];
+
+  let pts = (timecode as i64 + relative as i64).max(0) as u64 * info.timecode_scale / 1_000_000;
+
+  let data = if keep_data {
+    // we never write laced blocks, they hold several frames
+    if flags & 0x06 != 0 {
+      return Err(invalid("Laced blocks aren't supported"));
+    }
+
+    // a frame cut off by the end of the file is lost
+    let end = reader.end(header);
+    if end > reader.length {
+      return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut data = vec![0; end.saturating_sub(reader.position) as usize];
+    reader.read(&mut data)?;
+    Some(data)
+  } else {
+    reader.skip(header)?;
+    None
+  };
+
+  Ok(Block {
+    track,
+    pts,
+    flags,
+    data,
+  })
+}
+
+/// Count the frame of the block and pass it on, when it was read
+fn add_frame(
+  info: &mut FileInfo,
+  on_frame: &mut Option<&mut OnFrame<'_>>,
+  block: Block,
+  key: bool,
+) -> io::Result<()> {
+  info.count(block.track, block.pts, key);
+
+  match (on_frame, block.data) {
+    (Some(on_frame), Some(data)) => on_frame(
+      info,
+      Frame {
+        track: block.track,
+        pts: block.pts,
+        key,
+        data,
+      },
+    ),
+    _ => Ok(()),
+  }
+}
+
+/// Header of an EBML element
+#[derive(Debug)]
+struct Header {
+  id: u32,
+  /// `None` when the size is unknown
+  size: Option<u64>,
+  /// Position of the id
+  offset: u64,
+  /// Position of the data
+  start: u64,
+}
+
+/// Reads EBML values and keeps track of the position in the file
+struct Reader<R> {
+  inner: R,
+  position: u64,
+  length: u64,
+}
+
+impl<R: Read + Seek> Reader<R> {
+  fn read(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+    self.inner.read_exact(buffer)?;
+    self.position += buffer.len() as u64;
+    Ok(())
+  }
+
+  fn seek(&mut self, position: u64) -> io::Result<()> {
+    if position > self.length {
+      return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    self.inner.seek(SeekFrom::Start(position))?;
+    self.position = position;
+    Ok(())
+  }
+
+  /// Variable size integer with its marker bit, and its length
+  fn vint(&mut self) -> io::Result<(u64, usize)> {
+    let mut byte = [0];
+    self.read(&mut byte)?;
+
+    let length = byte[0].leading_zeros() as usize + 1;
+    if length > 8 {
+      return Err(invalid("Invalid variable size integer"));
+    }
+
+    let mut value = byte[0] as u64;
+    for _ in 1..length {
+      self.read(&mut byte)?;
+      value = value << 8 | byte[0] as u64;
+    }
+
+    Ok((value, length))
+  }
+
+  fn header(&mut self) -> io::Result<Header> {
+    let offset = self.position;
+
+    let (id, length) = self.vint()?;
+    if length > 4 {
+      return Err(invalid("Invalid element id"));
+    }
+
+    let (size, length) = self.vint()?;
+    let marker = 1 << (7 * length);
+    let size = size ^ marker;
+
+    Ok(Header {
+      id: id as u32,
+      // all ones means unknown
+      size: (size != marker - 1).then_some(size),
+      offset,
+      start: self.position,
+    })
+  }
+
+  /// Position after the element, an unknown size extends to the end of file
+  fn end(&self, header: &Header) -> u64 {
+    header
+      .size
+      .map_or(self.length, |size| header.start.saturating_add(size))
+  }
+
+  fn skip(&mut self, header: &Header) -> io::Result<()> {
+    let end = self.end(header);
+    self.seek(end)
+  }
+
+  fn data(&mut self, header: &Header) -> io::Result<Vec<u8>> {
+    let size = header
+      .size
+      .ok_or_else(|| invalid("Value of unknown size"))?;
+    if size > 1 << 20 {
+      return Err(invalid("Value too large"));
+    }
+
+    let mut data = vec![0; size as usize];
+    self.read(&mut data)?;
+    Ok(data)
+  }
+
+  fn uint(&mut self, header: &Header) -> io::Result<u64> {
+    let data = self.data(header)?;
+    if data.len() > 8 {
+      return Err(invalid("Integer too large"));
+    }
+
+    Ok(data.iter().fold(0, |value, &byte| value << 8 | byte as u64))
+  }
+
+  fn float(&mut self, header: &Header) -> io::Result<f64> {
+    let data = self.data(header)?;
+
+    match data.len() {
+      0 => Ok(0.),
+      4 => Ok(f32::from_be_bytes(data.try_into().unwrap()) as f64),
+      8 => Ok(f64::from_be_bytes(data.try_into().unwrap())),
+      _ => Err(invalid("Invalid float size")),
+    }
+  }
+
+  fn string(&mut self, header: &Header) -> io::Result<String> {
+    let data = self.data(header)?;
+    let string = String::from_utf8_lossy(&data);
+    Ok(string.trim_end_matches('\0').to_owned())
+  }
+}
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
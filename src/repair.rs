@@ -0,0 +1,136 @@
+//! Muxing unfinished recordings again
+//!
+//! A recording cut short by a crash has no cues and no duration, and the size
+//! of its last cluster was never written. Its frames are read back and muxed
+//! into a new file, which gets finished properly.
+
+use std::{
+  fs::OpenOptions,
+  io,
+  path::{Path, PathBuf},
+  sync::mpsc,
+  thread,
+};
+
+use webm::mux;
+
+use crate::{
+  audio::AudioFormat,
+  output::{self, Format},
+  probe::{self, FileInfo},
+  replay::{Packet, TrackKind},
+  yuv::{ColorSpace, Range},
+};
+
+/// How many frames can wait for the muxer
+const QUEUE_LENGTH: usize = 64;
+
+/// `recording.webm` → `recording-repaired.webm`, next to the original
+pub fn default_output(input: &Path) -> PathBuf {
+  let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+  input.with_file_name(format!("{stem}-repaired.webm"))
+}
+
+/// Write the frames of `input` into the new file `output` with their original
+/// timestamps, returns the description of the new file
+pub fn repair(input: &Path, output: &Path) -> io::Result<FileInfo> {
+  // the tracks have to be set up before the first frame
+  let info = probe::probe(input)?;
+  let (format, video, audio) = format(&info)?;
+
+  let out = OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .open(output)?;
+
+  let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+
+  let reader = thread::spawn({
+    let input = input.to_owned();
+    move || {
+      let result = probe::frames(&input, |_, frame| {
+        let kind = match frame.track {
+          track if track == video => TrackKind::Video,
+          track if Some(track) == audio => TrackKind::Audio,
+          _ => return Ok(()),
+        };
+
+        let packet = Packet {
+          kind,
+          data: frame.data,
+          pts: frame.pts,
+          key: frame.key,
+        };
+
+        // the muxer reports why it has stopped
+        sender
+          .send(Ok(packet))
+          .map_err(|_| io::ErrorKind::BrokenPipe.into())
+      });
+
+      if let Err(e) = result {
+        let _ = sender.send(Err(e));
+      }
+    }
+  });
+
+  let result = output::remux(out, Box::new(receiver.into_iter()), &format);
+  let _ = reader.join();
+
+  // a partly written file would look like a repaired one
+  if let Err(e) = result {
+    output::remove(output);
+    return Err(e);
+  }
+
+  probe::probe(output)
+}
+
+/// Format of the tracks, with the numbers of the video and the audio track
+///
+/// Only Opus audio can be muxed, other audio tracks are left out.
+fn format(info: &FileInfo) -> io::Result<(Format, u64, Option<u64>)> {
+  let video = info
+    .tracks
+    .iter()
+    .find(|track| track.kind == 1)
+    .ok_or_else(|| invalid("The file has no video track".into()))?;
+
+  let codec = match video.codec.as_str() {
+    "V_VP8" => mux::VideoCodecId::VP8,
+    "V_VP9" => mux::VideoCodecId::VP9,
+    codec => return Err(invalid(format!("Unsupported video codec {codec}"))),
+  };
+
+  let audio = info
+    .tracks
+    .iter()
+    .find(|track| track.kind == 2 && track.codec == "A_OPUS");
+
+  let format = Format {
+    width: video.width as u32,
+    height: video.height as u32,
+    codec,
+    colorspace: match video.matrix {
+      1 => Some(ColorSpace::Bt709),
+      5 | 6 => Some(ColorSpace::Bt601),
+      _ => None,
+    },
+    range: if video.range == 2 {
+      Range::Full
+    } else {
+      Range::Limited
+    },
+    audio: audio.map(|track| AudioFormat {
+      sample_rate: track.sample_rate as u32,
+      channels: track.channels as u16,
+      codec_private: track.codec_private.clone(),
+    }),
+  };
+
+  Ok((format, video.number, audio.map(|track| track.number)))
+}
+
+fn invalid(message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message)
+}
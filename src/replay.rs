@@ -0,0 +1,387 @@
+use std::{
+  collections::VecDeque,
+  fs::{self, File},
+  io::{self, BufReader, BufWriter, Read, Write},
+  path::{Path, PathBuf},
+  process,
+  time::Duration,
+};
+
+/// Minimal length of one on-disk part in milliseconds
+///
+/// Parts are cut only at keyframes. The buffer asks for one once a part is
+/// this long, so the parts don't grow with a long keyframe interval.
+const PART_LENGTH: u64 = 5_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackKind {
+  Video,
+  Audio,
+}
+
+/// Encoded frame kept in the replay buffer
+#[derive(Debug, Clone)]
+pub struct Packet {
+  pub kind: TrackKind,
+  pub data: Vec<u8>,
+  /// Presentation timestamp in milliseconds
+  pub pts: u64,
+  pub key: bool,
+}
+
+impl Packet {
+  /// Only video keyframes can start a clip, every audio packet is a keyframe
+  pub fn is_video_key(&self) -> bool {
+    self.kind == TrackKind::Video && self.key
+  }
+
+  /// Serialize the packet as `kind: u8, pts: u64, key: u8, length: u32, data`
+  fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    let length = u32::try_from(self.data.len())
+      .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Packet too large"))?;
+
+    let kind = match self.kind {
+      TrackKind::Video => 0,
+      TrackKind::Audio => 1,
+    };
+
+    writer.write_all(&[kind])?;
+    writer.write_all(&self.pts.to_le_bytes())?;
+    writer.write_all(&[u8::from(self.key)])?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(&self.data)
+  }
+
+  /// Read a packet written by [`Packet::write_to`], `None` on a clean EOF
+  fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+    let mut kind = [0; 1];
+    match reader.read_exact(&mut kind) {
+      Ok(()) => {}
+      Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+      Err(e) => return Err(e),
+    }
+
+    let kind = match kind[0] {
+      0 => TrackKind::Video,
+      1 => TrackKind::Audio,
+      _ => {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          "Unknown track kind",
+        ))
+      }
+    };
+
+    let mut pts = [0; 8];
+    reader.read_exact(&mut pts)?;
+
+    let mut key = [0; 1];
+    reader.read_exact(&mut key)?;
+
+    let mut length = [0; 4];
+    reader.read_exact(&mut length)?;
+
+    let mut data = vec![0; u32::from_le_bytes(length) as usize];
+    reader.read_exact(&mut data)?;
+
+    Ok(Some(Self {
+      kind,
+      data,
+      pts: u64::from_le_bytes(pts),
+      key: key[0] != 0,
+    }))
+  }
+}
+
+/// Packets of a replay buffer snapshot in the order they were pushed
+pub type Packets = Box<dyn Iterator<Item = io::Result<Packet>> + Send>;
+
+/// Rolling buffer that keeps roughly the last N seconds of encoded video
+/// and audio
+///
+/// The buffer is trimmed only by whole keyframe groups, so it always starts
+/// at a video keyframe and every saved clip can be decoded from its first frame.
+/// Because of that it may hold a bit more than requested.
+pub trait ReplayBuffer: Send {
+  fn push(&mut self, packet: Packet) -> io::Result<()>;
+
+  /// Capture the current content of the buffer, so it can be saved in the
+  /// background while the recording continues
+  fn snapshot(&mut self) -> io::Result<Packets>;
+
+  /// Whether the buffer is waiting for a video keyframe to cut it
+  fn wants_keyframe(&self) -> bool {
+    false
+  }
+}
+
+/// Replay buffer held completely in memory
+pub struct MemoryBuffer {
+  packets: VecDeque<Packet>,
+  length: u64,
+}
+
+impl MemoryBuffer {
+  pub fn new(length: Duration) -> Self {
+    Self {
+      packets: VecDeque::new(),
+      length: length.as_millis() as u64,
+    }
+  }
+
+  /// Drop the oldest keyframe groups that are no longer needed to cover
+  /// `length` milliseconds
+  fn trim(&mut self) {
+    let Some(newest) = self.packets.back().map(|p| p.pts) else { return; };
+    let cutoff = newest.saturating_sub(self.length);
+
+    loop {
+      let next_key = self
+        .packets
+        .iter()
+        .skip(1)
+        .position(Packet::is_video_key)
+        .map(|i| i + 1);
+
+      match next_key {
+        Some(i) if self.packets[i].pts <= cutoff => {
+          self.packets.drain(..i);
+        }
+        _ => break,
+      }
+    }
+  }
+}
+
+impl ReplayBuffer for MemoryBuffer {
+  fn push(&mut self, packet: Packet) -> io::Result<()> {
+    // the clip has to start with a keyframe, anything before it is useless
+    if self.packets.is_empty() && !packet.is_video_key() {
+      return Ok(());
+    }
+
+    self.packets.push_back(packet);
+    self.trim();
+
+    Ok(())
+  }
+
+  fn snapshot(&mut self) -> io::Result<Packets> {
+    let packets: Vec<_> = self.packets.iter().cloned().collect();
+    Ok(Box::new(packets.into_iter().map(Ok)))
+  }
+}
+
+/// One keyframe-aligned file of a [`DiskBuffer`]
+struct Part {
+  path: PathBuf,
+  /// Timestamp of the first video keyframe
+  start: u64,
+}
+
+/// Replay buffer stored as a ring of keyframe-aligned part files
+///
+/// Every part starts with a keyframe and holds at least [`PART_LENGTH`]
+/// milliseconds of video. Parts are deleted once they fall out of the replay
+/// window.
+pub struct DiskBuffer {
+  dir: PathBuf,
+  parts: VecDeque<Part>,
+  writer: Option<BufWriter<File>>,
+  next_index: u64,
+  length: u64,
+  /// Timestamp of the last video packet
+  newest: u64,
+}
+
+impl DiskBuffer {
+  /// Folder for the buffer of `display` in `tmp`, not shared with any other
+  /// recording
+  pub fn dir(tmp: &Path, display: usize) -> PathBuf {
+    tmp.join(format!("replay-{}-{display}", process::id()))
+  }
+
+  /// Create the buffer in `dir`, which must not be shared with other
+  /// recordings, see [`DiskBuffer::dir`]
+  pub fn new(dir: PathBuf, length: Duration) -> io::Result<Self> {
+    fs::create_dir_all(&dir)?;
+
+    Ok(Self {
+      dir,
+      parts: VecDeque::new(),
+      writer: None,
+      next_index: 0,
+      length: length.as_millis() as u64,
+      newest: 0,
+    })
+  }
+
+  fn start_part(&mut self, start: u64) -> io::Result<()> {
+    if let Some(mut writer) = self.writer.take() {
+      writer.flush()?;
+    }
+
+    let path = self.dir.join(format!("part{:06}", self.next_index));
+    self.next_index += 1;
+
+    self.writer = Some(BufWriter::new(File::create(&path)?));
+    self.parts.push_back(Part { path, start });
+
+    Ok(())
+  }
+
+  /// Delete the oldest parts that are no longer needed to cover `length`
+  /// milliseconds
+  fn expire(&mut self, newest: u64) -> io::Result<()> {
+    let cutoff = newest.saturating_sub(self.length);
+
+    while self.parts.len() > 1 && self.parts[1].start <= cutoff {
+      if let Some(part) = self.parts.pop_front() {
+        fs::remove_file(part.path)?;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl ReplayBuffer for DiskBuffer {
+  fn push(&mut self, packet: Packet) -> io::Result<()> {
+    if packet.is_video_key() {
+      let part_full = self
+        .parts
+        .back()
+        .map_or(true, |part| packet.pts - part.start >= PART_LENGTH);
+
+      if part_full {
+        self.start_part(packet.pts)?;
+      }
+    }
+
+    // the parts have to start with a keyframe, anything before it is useless
+    let Some(writer) = &mut self.writer else { return Ok(()); };
+
+    packet.write_to(writer)?;
+
+    // the keyframe that cuts the part can't be older than this
+    if packet.kind == TrackKind::Video {
+      self.newest = packet.pts;
+    }
+
+    self.expire(packet.pts)
+  }
+
+  fn snapshot(&mut self) -> io::Result<Packets> {
+    if let Some(writer) = &mut self.writer {
+      writer.flush()?;
+    }
+
+    // The parts are opened right away, so they stay readable even after they
+    // get deleted. The last one is still being written, so only the part
+    // that is already there is taken.
+    let readers = self
+      .parts
+      .iter()
+      .map(|part| {
+        let file = File::open(&part.path)?;
+        let length = file.metadata()?.len();
+        Ok(BufReader::new(file).take(length))
+      })
+      .collect::<io::Result<VecDeque<_>>>()?;
+
+    Ok(Box::new(PartReader { readers }))
+  }
+
+  fn wants_keyframe(&self) -> bool {
+    self.parts.back().map_or(false, |part| {
+      self.newest.saturating_sub(part.start) >= PART_LENGTH
+    })
+  }
+}
+
+impl Drop for DiskBuffer {
+  fn drop(&mut self) {
+    for part in &self.parts {
+      let _ = fs::remove_file(&part.path);
+    }
+    let _ = fs::remove_dir(&self.dir);
+  }
+}
+
+/// Iterator over the packets stored in a sequence of parts
+struct PartReader {
+  readers: VecDeque<io::Take<BufReader<File>>>,
+}
+
+impl Iterator for PartReader {
+  type Item = io::Result<Packet>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      let reader = self.readers.front_mut()?;
+
+      match Packet::read_from(reader) {
+        Ok(Some(packet)) => return Some(Ok(packet)),
+        Ok(None) => {
+          self.readers.pop_front();
+        }
+        Err(e) => {
+          self.readers.clear();
+          return Some(Err(e));
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+
+  use super::*;
+
+  fn packet(kind: TrackKind, pts: u64, key: bool, display: u8) -> Packet {
+    Packet {
+      kind,
+      data: vec![display; 3],
+      pts,
+      key,
+    }
+  }
+
+  #[test]
+  fn displays_have_their_own_disk_buffers() {
+    let tmp = env::temp_dir().join(format!("shadowplay-test-{}", process::id()));
+    let length = Duration::from_millis(3 * PART_LENGTH);
+    let mut buffers = [
+      DiskBuffer::new(DiskBuffer::dir(&tmp, 0), length).unwrap(),
+      DiskBuffer::new(DiskBuffer::dir(&tmp, 1), length).unwrap(),
+    ];
+
+    // long enough to start and expire several parts in both
+    for pts in (0..8 * PART_LENGTH).step_by(100) {
+      for (display, buffer) in buffers.iter_mut().enumerate() {
+        let key = pts % 1000 == 0;
+        buffer
+          .push(packet(TrackKind::Video, pts, key, display as u8))
+          .unwrap();
+        buffer
+          .push(packet(TrackKind::Audio, pts + 10, true, display as u8))
+          .unwrap();
+      }
+    }
+
+    for (display, buffer) in buffers.iter_mut().enumerate() {
+      let packets: Vec<_> = buffer.snapshot().unwrap().map(Result::unwrap).collect();
+
+      assert!(packets[0].is_video_key());
+      assert!(packets
+        .iter()
+        .all(|packet| packet.data == [display as u8; 3]));
+      assert_eq!(packets.last().unwrap().pts, 8 * PART_LENGTH - 90);
+    }
+
+    drop(buffers);
+    let _ = fs::remove_dir(tmp);
+  }
+}
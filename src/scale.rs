@@ -0,0 +1,235 @@
+//! Scaling of the captured frames to the size of the video
+//!
+//! The frames are scaled row by row, see [`Converter`](crate::convert::Converter).
+
+use std::fmt;
+
+use clap::ValueEnum;
+
+/// How the pixels of the scaled frame are computed from the captured ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum Filter {
+  /// The closest pixel, fastest but blocky
+  Nearest,
+  /// Blend of the four closest pixels, smooth unless shrinking below half
+  #[default]
+  Bilinear,
+  /// Average of all the covered pixels, the sharpest for shrinking
+  Area,
+}
+
+impl fmt::Display for Filter {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let string = match self {
+      Self::Nearest => "nearest",
+      Self::Bilinear => "bilinear",
+      Self::Area => "area",
+    };
+    write!(f, "{string}")
+  }
+}
+
+/// Size to scale to, a missing side keeps the aspect ratio
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+  width: Option<usize>,
+  height: Option<usize>,
+}
+
+impl Size {
+  /// Parse `WIDTHxHEIGHT`, `WIDTHx` or `xHEIGHT`
+  pub fn parse(s: &str) -> Result<Self, String> {
+    let Some((width, height)) = s.split_once('x') else {
+      return Err(format!("Expected WIDTHxHEIGHT, got `{s}`"));
+    };
+
+    let parse_side = |text: &str| match text.trim() {
+      "" => Ok(None),
+      text => match text.parse() {
+        Ok(0) => Err(format!("The size `{s}` is empty")),
+        Ok(side) => Ok(Some(side)),
+        Err(e) => Err(format!("Invalid size `{s}`: {e}")),
+      },
+    };
+
+    let size = Self {
+      width: parse_side(width)?,
+      height: parse_side(height)?,
+    };
+
+    if size.width.is_none() && size.height.is_none() {
+      return Err(format!("The size `{s}` needs a width or a height"));
+    }
+
+    Ok(size)
+  }
+
+  /// Width and height when scaling a frame of the given size
+  ///
+  /// The side that keeps the aspect ratio is rounded to an even number, so it
+  /// isn't cut short by the chroma subsampling.
+  pub fn resolve(self, width: usize, height: usize) -> (usize, usize) {
+    let keep_ratio =
+      |side: usize, from: usize, to: usize| ((side * to + from) / (2 * from) * 2).max(2);
+
+    match (self.width, self.height) {
+      (Some(new_width), Some(new_height)) => (new_width, new_height),
+      (Some(new_width), None) => (new_width, keep_ratio(height, width, new_width)),
+      (None, Some(new_height)) => (keep_ratio(width, height, new_height), new_height),
+      (None, None) => (width, height),
+    }
+  }
+}
+
+impl fmt::Display for Size {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if let Some(width) = self.width {
+      write!(f, "{width}")?;
+    }
+    write!(f, "x")?;
+    if let Some(height) = self.height {
+      write!(f, "{height}")?;
+    }
+    Ok(())
+  }
+}
+
+/// Source pixels of one scaled pixel along one axis
+#[derive(Debug, Clone, Copy)]
+struct Span {
+  first: usize,
+  /// Inclusive, the same as `first` for a single pixel
+  last: usize,
+  /// Weight of `last` in 1/256 when blending the two
+  weight: u32,
+}
+
+/// Scales BGRA frames of one size to another
+#[derive(Debug, Clone)]
+pub struct Scaler {
+  filter: Filter,
+  source_width: usize,
+  source_height: usize,
+  columns: Vec<Span>,
+  rows: Vec<Span>,
+}
+
+impl Scaler {
+  pub fn new(filter: Filter, source: (usize, usize), size: (usize, usize)) -> Self {
+    Self {
+      filter,
+      source_width: source.0,
+      source_height: source.1,
+      columns: spans(filter, source.0, size.0),
+      rows: spans(filter, source.1, size.1),
+    }
+  }
+
+  pub fn source_size(&self) -> (usize, usize) {
+    (self.source_width, self.source_height)
+  }
+
+  /// Compute the row of the scaled frame from the whole captured frame,
+  /// whose rows are `stride` bytes long
+  pub fn scale_row(&self, src: &[u8], stride: usize, y: usize, out: &mut [u8]) {
+    let length = self.source_width * 4;
+    let row = |y: usize| &src[y * stride..][..length];
+    let span = self.rows[y];
+
+    let pixels = out.chunks_exact_mut(4).zip(&self.columns);
+
+    match self.filter {
+      Filter::Nearest => {
+        let row = row(span.first);
+        for (out, column) in pixels {
+          out.copy_from_slice(&row[column.first * 4..][..4]);
+        }
+      }
+      Filter::Bilinear => {
+        let (top, bottom) = (row(span.first), row(span.last));
+        for (out, column) in pixels {
+          let (left, right) = (column.first * 4, column.last * 4);
+          for channel in 0..4 {
+            let blend = |row: &[u8]| lerp(row[left + channel], row[right + channel], column.weight);
+            out[channel] = lerp(blend(top), blend(bottom), span.weight);
+          }
+        }
+      }
+      Filter::Area => {
+        for (out, column) in pixels {
+          let mut sums = [0_u32; 4];
+          for y in span.first..=span.last {
+            for pixel in row(y)[column.first * 4..(column.last + 1) * 4].chunks_exact(4) {
+              for (sum, value) in sums.iter_mut().zip(pixel) {
+                *sum += u32::from(*value);
+              }
+            }
+          }
+
+          let count = ((span.last - span.first + 1) * (column.last - column.first + 1)) as u32;
+          for (out, sum) in out.iter_mut().zip(sums) {
+            *out = ((sum + count / 2) / count) as u8;
+          }
+        }
+      }
+    }
+  }
+}
+
+/// For each of the `to` scaled pixels, the `from` source pixels it's made of
+fn spans(filter: Filter, from: usize, to: usize) -> Vec<Span> {
+  (0..to)
+    .map(|i| match filter {
+      Filter::Nearest => {
+        // the pixel under the centre of the scaled one
+        let first = ((2 * i + 1) * from / (2 * to)).min(from - 1);
+        Span {
+          first,
+          last: first,
+          weight: 0,
+        }
+      }
+      Filter::Bilinear => {
+        // the centre of the scaled pixel in 1/256 of the source pixels,
+        // between the centres of two of them
+        let centre = ((2 * i + 1) * from * 256 / (2 * to)).saturating_sub(128);
+        let first = (centre / 256).min(from - 1);
+        Span {
+          first,
+          last: (first + 1).min(from - 1),
+          weight: (centre % 256) as u32,
+        }
+      }
+      Filter::Area => {
+        let first = (i * from / to).min(from - 1);
+        let end = ((i + 1) * from / to).clamp(first + 1, from);
+        Span {
+          first,
+          last: end - 1,
+          weight: 0,
+        }
+      }
+    })
+    .collect()
+}
+
+fn lerp(a: u8, b: u8, weight: u32) -> u8 {
+  ((u32::from(a) * (256 - weight) + u32::from(b) * weight + 128) / 256) as u8
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resolve_rounds_the_kept_side_to_even() {
+    let resolve = |size: &str, width, height| Size::parse(size).unwrap().resolve(width, height);
+
+    assert_eq!(resolve("x720", 1920, 1080), (1280, 720));
+    assert_eq!(resolve("x719", 1920, 1080), (1278, 719));
+    assert_eq!(resolve("x101", 1920, 1080), (180, 101));
+    assert_eq!(resolve("1280x", 1366, 768), (1280, 720));
+    assert_eq!(resolve("3x", 1920, 1080), (3, 2));
+    assert_eq!(resolve("641x363", 1920, 1080), (641, 363));
+  }
+}
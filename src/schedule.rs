@@ -0,0 +1,124 @@
+use std::{
+  sync::{Arc, Mutex},
+  thread,
+  time::{Duration, Instant},
+};
+
+/// Time since the start of the recording, standing still while it's paused
+#[derive(Debug)]
+pub struct Clock {
+  state: Mutex<ClockState>,
+}
+
+#[derive(Debug)]
+struct ClockState {
+  start: Instant,
+  paused_since: Option<Instant>,
+  /// Time spent paused so far
+  paused: Duration,
+}
+
+impl Clock {
+  pub fn new() -> Self {
+    Self {
+      state: Mutex::new(ClockState {
+        start: Instant::now(),
+        paused_since: None,
+        paused: Duration::ZERO,
+      }),
+    }
+  }
+
+  /// Start counting from zero again
+  pub fn reset(&self) {
+    *self.state.lock().unwrap() = ClockState {
+      start: Instant::now(),
+      paused_since: None,
+      paused: Duration::ZERO,
+    };
+  }
+
+  pub fn elapsed(&self) -> Duration {
+    let state = self.state.lock().unwrap();
+    let now = state.paused_since.unwrap_or_else(Instant::now);
+
+    now
+      .saturating_duration_since(state.start)
+      .saturating_sub(state.paused)
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.state.lock().unwrap().paused_since.is_some()
+  }
+
+  /// Pause or resume the clock, returns whether it's paused now
+  pub fn toggle_pause(&self) -> bool {
+    let mut state = self.state.lock().unwrap();
+
+    match state.paused_since.take() {
+      Some(since) => state.paused += since.elapsed(),
+      None => state.paused_since = Some(Instant::now()),
+    }
+
+    state.paused_since.is_some()
+  }
+}
+
+/// Point on the grid of frame times
+#[derive(Debug, Clone, Copy)]
+pub struct Tick {
+  /// Timestamp in milliseconds since the start
+  pub pts: u64,
+  /// Ticks skipped since the previous one, because the capture fell behind
+  pub missed: u64,
+}
+
+/// Clock ticking at exact multiples of the frame interval
+///
+/// The time of every tick is computed from the start, so the errors of the
+/// individual sleeps don't add up.
+pub struct Scheduler {
+  clock: Arc<Clock>,
+  fps: u64,
+  next: u64,
+}
+
+impl Scheduler {
+  pub fn new(clock: Arc<Clock>, fps: u64) -> Self {
+    Self {
+      clock,
+      fps: fps.max(1),
+      next: 0,
+    }
+  }
+
+  /// Time of the tick since the start
+  fn time(&self, tick: u64) -> Duration {
+    Duration::from_nanos(tick * 1_000_000_000 / self.fps)
+  }
+
+  /// Sleep until the next tick
+  ///
+  /// When running late by more than a whole interval, the ticks that have
+  /// passed are skipped and only the latest one is returned.
+  pub fn wait(&mut self) -> Tick {
+    let elapsed = self.clock.elapsed();
+    let latest = elapsed.as_nanos() as u64 * self.fps / 1_000_000_000;
+
+    let tick = self.next.max(latest);
+    let missed = tick - self.next;
+
+    if let Some(remaining) = self.time(tick).checked_sub(elapsed) {
+      thread::sleep(remaining);
+    }
+
+    self.next = tick + 1;
+
+    // WebM stores the timestamps in milliseconds, so this is as exact as the
+    // file can get
+    Tick {
+      pts: self.time(tick).as_millis() as u64,
+      missed,
+    }
+  }
+}
@@ -0,0 +1,51 @@
+//! Screenshots of the captured frames
+
+use std::{fs::File, io, thread};
+
+use crate::{error, filename::Namer};
+
+/// Size and layout of the captured frames
+#[derive(Debug, Clone, Copy)]
+pub struct Geometry {
+  pub width: usize,
+  pub height: usize,
+  /// Length of one row in bytes
+  pub stride: usize,
+}
+
+/// Save a BGRA frame into a new PNG file
+///
+/// Only the copy of the frame is made right away, the file is written on a
+/// separate thread, so the recording isn't interrupted.
+pub fn save(frame: &[u8], geometry: Geometry, namer: Namer) {
+  let mut rgba = Vec::with_capacity(geometry.width * geometry.height * 4);
+
+  for row in frame.chunks(geometry.stride).take(geometry.height) {
+    for pixel in row[..geometry.width * 4].chunks_exact(4) {
+      rgba.extend_from_slice(&[pixel[2], pixel[1], pixel[0], 255]);
+    }
+  }
+
+  thread::spawn(move || {
+    let result = namer
+      .create()
+      .and_then(|(path, out)| write_png(out, &rgba, geometry).map(|()| path));
+
+    match result {
+      Ok(path) => println!("Screenshot saved to {path:?}"),
+      Err(e) => error(format!("Can't save the screenshot: {e}")),
+    }
+  });
+}
+
+fn write_png(out: File, rgba: &[u8], geometry: Geometry) -> io::Result<()> {
+  let mut encoder = png::Encoder::new(out, geometry.width as u32, geometry.height as u32);
+  encoder.set_color(png::ColorType::Rgba);
+  encoder.set_depth(png::BitDepth::Eight);
+
+  let mut writer = encoder.write_header()?;
+  writer.write_image_data(rgba)?;
+  writer.finish()?;
+
+  Ok(())
+}
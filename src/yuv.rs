@@ -0,0 +1,216 @@
+use std::fmt;
+
+use clap::ValueEnum;
+use vpx_sys::vpx_img_fmt;
+
+/// Standard defining how RGB is turned into YUV
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum ColorSpace {
+  /// ITU-R BT.601, meant for SD video
+  Bt601,
+  /// ITU-R BT.709, meant for HD video
+  #[default]
+  Bt709,
+}
+
+impl fmt::Display for ColorSpace {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let string = match self {
+      Self::Bt601 => "bt601",
+      Self::Bt709 => "bt709",
+    };
+    write!(f, "{string}")
+  }
+}
+
+/// Range of the YUV values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum Range {
+  /// Y in 16-235, U and V in 16-240, expected by most players
+  #[default]
+  Limited,
+  /// Y, U and V all in 0-255
+  Full,
+}
+
+impl fmt::Display for Range {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let string = match self {
+      Self::Limited => "limited",
+      Self::Full => "full",
+    };
+    write!(f, "{string}")
+  }
+}
+
+/// Arrangement of the planes in a [`YuvFrame`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaneLayout {
+  /// Full resolution Y plane followed by U and V planes subsampled by two
+  /// in both directions
+  I420,
+  /// Y, U and V planes all in full resolution
+  I444,
+  /// Full resolution Y plane followed by a single plane of interleaved U and
+  /// V samples, subsampled like in I420
+  NV12,
+}
+
+impl PlaneLayout {
+  pub fn vpx_format(self) -> vpx_img_fmt {
+    match self {
+      Self::I420 => vpx_img_fmt::VPX_IMG_FMT_I420,
+      Self::I444 => vpx_img_fmt::VPX_IMG_FMT_I444,
+      Self::NV12 => vpx_img_fmt::VPX_IMG_FMT_NV12,
+    }
+  }
+
+  /// Size of the chroma planes in samples
+  ///
+  /// The subsampled sizes are rounded up, so the last odd row and column
+  /// still gets its own chroma sample.
+  pub fn chroma_size(self, width: usize, height: usize) -> (usize, usize) {
+    match self {
+      Self::I420 | Self::NV12 => ((width + 1) / 2, (height + 1) / 2),
+      Self::I444 => (width, height),
+    }
+  }
+
+  /// Length of a row of each plane without any padding
+  ///
+  /// NV12 has only two planes, the third stride is zero.
+  pub fn packed_strides(self, width: usize, height: usize) -> [usize; 3] {
+    let (chroma_width, _) = self.chroma_size(width, height);
+
+    match self {
+      Self::I420 | Self::I444 => [width, chroma_width, chroma_width],
+      Self::NV12 => [width, chroma_width * 2, 0],
+    }
+  }
+}
+
+impl fmt::Display for PlaneLayout {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let string = match self {
+      Self::I420 => "I420",
+      Self::I444 => "I444",
+      Self::NV12 => "NV12",
+    };
+    write!(f, "{string}")
+  }
+}
+
+/// Planar YUV image together with the description of its layout
+///
+/// The planes are stored one after another, without any padding between the
+/// rows unless the frame is made by [`YuvFrame::with_strides`]. The
+/// conversions and the encoder go through [`YuvFrame::strides`] and
+/// [`YuvFrame::planes`], so they don't depend on the planes being packed.
+#[derive(Debug, Clone)]
+pub struct YuvFrame {
+  layout: PlaneLayout,
+  width: usize,
+  height: usize,
+  strides: [usize; 3],
+  data: Vec<u8>,
+}
+
+impl YuvFrame {
+  /// Allocate a black frame
+  pub fn new(layout: PlaneLayout, width: usize, height: usize) -> Self {
+    Self::with_strides(layout, width, height, layout.packed_strides(width, height))
+  }
+
+  /// Allocate a black frame with the rows of the planes padded to the given
+  /// lengths in bytes, e.g. to the alignment an encoder wants
+  ///
+  /// Panics if a stride is shorter than the row of its plane.
+  pub fn with_strides(
+    layout: PlaneLayout,
+    width: usize,
+    height: usize,
+    strides: [usize; 3],
+  ) -> Self {
+    let packed = layout.packed_strides(width, height);
+    assert!(
+      strides
+        .iter()
+        .zip(packed)
+        .all(|(&stride, packed)| stride >= packed),
+      "strides {strides:?} are too short for a {width}x{height} {layout} frame"
+    );
+
+    let (_, chroma_height) = layout.chroma_size(width, height);
+    let luma = strides[0] * height;
+    let chroma = (strides[1] + strides[2]) * chroma_height;
+
+    let mut data = vec![16; luma + chroma];
+    data[luma..].fill(128);
+
+    Self {
+      layout,
+      width,
+      height,
+      strides,
+      data,
+    }
+  }
+
+  pub fn layout(&self) -> PlaneLayout {
+    self.layout
+  }
+
+  pub fn width(&self) -> usize {
+    self.width
+  }
+
+  pub fn height(&self) -> usize {
+    self.height
+  }
+
+  /// Length of a row of each plane in bytes
+  ///
+  /// NV12 has only two planes, the third stride is zero.
+  pub fn strides(&self) -> [usize; 3] {
+    self.strides
+  }
+
+  pub fn data(&self) -> &[u8] {
+    &self.data
+  }
+
+  /// Y, U and V planes, laid out like in [`YuvFrame::planes_mut`]
+  pub fn planes(&self) -> [&[u8]; 3] {
+    let (_, chroma_height) = self.layout.chroma_size(self.width, self.height);
+    let chroma = self.strides[1] * chroma_height;
+
+    let (y, uv) = self.data.split_at(self.strides[0] * self.height);
+
+    match self.layout {
+      PlaneLayout::I420 | PlaneLayout::I444 => {
+        let (u, v) = uv.split_at(chroma);
+        [y, u, v]
+      }
+      PlaneLayout::NV12 => [y, uv, &[]],
+    }
+  }
+
+  /// Mutable Y, U and V planes
+  ///
+  /// For NV12 the second plane holds the interleaved U and V samples and the
+  /// third one is empty.
+  pub fn planes_mut(&mut self) -> [&mut [u8]; 3] {
+    let (_, chroma_height) = self.layout.chroma_size(self.width, self.height);
+    let chroma = self.strides[1] * chroma_height;
+
+    let (y, uv) = self.data.split_at_mut(self.strides[0] * self.height);
+
+    match self.layout {
+      PlaneLayout::I420 | PlaneLayout::I444 => {
+        let (u, v) = uv.split_at_mut(chroma);
+        [y, u, v]
+      }
+      PlaneLayout::NV12 => [y, uv, &mut []],
+    }
+  }
+}